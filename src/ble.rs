@@ -1,9 +1,19 @@
+use crate::ble_message_ext::split_into_writes;
+use crate::error::TrueGearError;
+use crate::transport::{
+    CONNECTION_STATE_CHANNEL_CAPACITY, ConnectionState, DEFAULT_MAX_WRITE_SIZE,
+    OnConnectedCallback, OnMessageReceivedCallback, TrueGearTransport,
+};
 use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter, WriteType};
-use btleplug::platform::{Manager, Peripheral};
+use btleplug::platform::{Adapter, Manager, Peripheral};
 use futures::stream::StreamExt;
-use std::error::Error;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, broadcast, watch};
+use tokio::time::Instant;
 use uuid::{Uuid, uuid};
 
 const SERVICE_UUID_CENTER: Uuid = uuid!("6e400001-b5a3-f393-e0a9-e50e24dcca9e");
@@ -12,93 +22,587 @@ const SERVICE_UUID_CENTER_WRITE_CHARACTERISTICS: Uuid =
 const SERVICE_UUID_CENTER_NOTIFY_CHARACTERISTICS: Uuid =
     uuid!("6e400003-b5a3-f393-e0a9-e50e24dcca9e");
 
-type OnConnectedCallback = Box<dyn Fn() + Send + Sync>;
-type OnMessageReceivedCallback = Box<dyn Fn(&[u8]) + Send + Sync>;
+// Delay before retrying a failed connection, doubled after every failure
+const RECONNECT_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+// How long a single scan looks for the device before it counts as a failure;
+// connecting to what it found is not counted
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+// How often the link is polled in case the adapter misses a disconnect event
+const CONNECTION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// How long the remembered device is waited for before other vests are tried
+const PREFERRED_DEVICE_GRACE: Duration = Duration::from_secs(3);
+// Stores the last connected device, relative to the home directory
+const LAST_DEVICE_FILE: &str = ".truegear-cli/last_device";
+// How long `scan` spends checking the characteristics of one device
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Selects which vest the connection uses, and how data is written to it.
+#[derive(Debug, Clone)]
+pub struct BleOptions {
+    /// MAC address (or platform device id) or exact name of the vest.
+    /// Defaults to the last used vest, then to any `Truegear_C` vest.
+    pub device: Option<String>,
+    /// Largest payload of one write; longer data is split into several.
+    pub max_write_size: usize,
+    /// Pause between the writes that make up one `send_data`.
+    pub write_interval: Duration,
+    /// How many times a failed write is retried, with response.
+    pub write_retries: u32,
+}
+
+impl Default for BleOptions {
+    fn default() -> Self {
+        BleOptions {
+            device: None,
+            max_write_size: DEFAULT_MAX_WRITE_SIZE,
+            write_interval: Duration::from_millis(20),
+            write_retries: 0,
+        }
+    }
+}
+
+fn last_device_path() -> Option<PathBuf> {
+    std::env::home_dir().map(|home| home.join(LAST_DEVICE_FILE))
+}
+
+fn load_last_device() -> Option<String> {
+    let path = last_device_path()?;
+    let device = std::fs::read_to_string(path).ok()?;
+    let device = device.trim();
+    (!device.is_empty()).then(|| device.to_string())
+}
+
+fn save_last_device(device: &str) {
+    let Some(path) = last_device_path() else {
+        return;
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, device));
+    if let Err(e) = result {
+        tracing::warn!("Failed to remember device in {}: {}", path.display(), e);
+    }
+}
+
+fn is_address(selector: &str) -> bool {
+    let parts: Vec<&str> = selector.split([':', '-']).collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Picks an adapter by index or by a substring of its name, or the first
+/// adapter if `selector` is `None`.
+async fn select_adapter(
+    manager: &Manager,
+    selector: Option<&str>,
+) -> Result<Adapter, TrueGearError> {
+    let adapters = manager.adapters().await?;
+    if adapters.is_empty() {
+        return Err(TrueGearError::AdapterNotFound(
+            "No Bluetooth adapter found".to_string(),
+        ));
+    }
+
+    let Some(selector) = selector else {
+        return Ok(adapters.into_iter().next().unwrap());
+    };
+
+    let mut names = Vec::with_capacity(adapters.len());
+    for adapter in &adapters {
+        names.push(adapter.adapter_info().await.unwrap_or_default());
+    }
+
+    let position = match selector.parse::<usize>() {
+        Ok(index) => (index < adapters.len()).then_some(index),
+        Err(_) => names.iter().position(|name| name.contains(selector)),
+    };
+
+    match position {
+        Some(index) => {
+            tracing::debug!("Using adapter {}: {}", index, names[index]);
+            Ok(adapters.into_iter().nth(index).unwrap())
+        }
+        None => {
+            let available: Vec<String> = names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| format!("{index}: {name}"))
+                .collect();
+            Err(TrueGearError::AdapterNotFound(format!(
+                "Bluetooth adapter {:?} not found (available: {})",
+                selector,
+                available.join(", ")
+            )))
+        }
+    }
+}
+
+/// The adapter the connections use, and the scan they share on it, so that
+/// one connection finishing its scan does not end another's.
+#[derive(Clone)]
+pub struct BleScanner {
+    /// Adapter index (starting at 0) or a substring of the adapter name.
+    /// Defaults to the first adapter.
+    adapter: Option<String>,
+    central: Arc<Mutex<Option<Adapter>>>,
+    /// How many connections are scanning.
+    scans: Arc<Mutex<usize>>,
+}
+
+impl BleScanner {
+    pub fn new(adapter: Option<String>) -> Self {
+        BleScanner {
+            adapter,
+            central: Arc::new(Mutex::new(None)),
+            scans: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// The selected adapter, looked up on first use.
+    async fn central(&self) -> Result<Adapter, TrueGearError> {
+        let mut central = self.central.lock().await;
+        if let Some(central) = &*central {
+            return Ok(central.clone());
+        }
+
+        let manager = Manager::new().await?;
+        let selected = select_adapter(&manager, self.adapter.as_deref()).await?;
+        *central = Some(selected.clone());
+        Ok(selected)
+    }
+
+    /// Starts scanning for vests, unless another connection already is.
+    async fn start_scan(&self, central: &Adapter) -> Result<(), TrueGearError> {
+        let mut scans = self.scans.lock().await;
+        if *scans == 0 {
+            central
+                .start_scan(ScanFilter {
+                    services: vec![SERVICE_UUID_CENTER],
+                })
+                .await?;
+        }
+        *scans += 1;
+        Ok(())
+    }
+
+    /// Stops scanning once no other connection is.
+    async fn stop_scan(&self, central: &Adapter) -> Result<(), TrueGearError> {
+        let mut scans = self.scans.lock().await;
+        *scans = scans.saturating_sub(1);
+        if *scans == 0 {
+            central.stop_scan().await?;
+        }
+        Ok(())
+    }
+}
+
+/// A TrueGear peripheral reported by `scan`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedDevice {
+    pub id: String,
+    pub address: String,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+    pub write_characteristic: bool,
+    pub notify_characteristic: bool,
+    /// Why the characteristics could not be checked, if they could not.
+    pub probe_error: Option<String>,
+}
+
+/// Connects to `peripheral` just long enough to check which of the TrueGear
+/// characteristics it exposes.
+async fn probe_characteristics(peripheral: &Peripheral) -> Result<(bool, bool), TrueGearError> {
+    peripheral.connect().await?;
+    let discovered = peripheral.discover_services().await;
+    let characteristics = peripheral.characteristics();
+    peripheral.disconnect().await?;
+    discovered?;
+
+    let has = |uuid: Uuid| {
+        characteristics
+            .iter()
+            .any(|c| c.service_uuid == SERVICE_UUID_CENTER && c.uuid == uuid)
+    };
+    Ok((
+        has(SERVICE_UUID_CENTER_WRITE_CHARACTERISTICS),
+        has(SERVICE_UUID_CENTER_NOTIFY_CHARACTERISTICS),
+    ))
+}
+
+/// Scans for TrueGear peripherals for `duration`, then checks each one for
+/// the write and notify characteristics.
+pub async fn scan(
+    adapter: Option<&str>,
+    duration: Duration,
+) -> Result<Vec<ScannedDevice>, TrueGearError> {
+    let manager = Manager::new().await?;
+    let central = select_adapter(&manager, adapter).await?;
+    let mut events = central.events().await?;
+
+    central
+        .start_scan(ScanFilter {
+            services: vec![SERVICE_UUID_CENTER],
+        })
+        .await?;
+
+    let mut ids = Vec::new();
+    let collect = async {
+        while let Some(event) = events.next().await {
+            if let CentralEvent::DeviceDiscovered(id) = event
+                && !ids.contains(&id)
+            {
+                tracing::debug!("DeviceDiscovered: {:?}", id);
+                ids.push(id);
+            }
+        }
+    };
+    let _ = tokio::time::timeout(duration, collect).await;
+
+    central.stop_scan().await?;
+
+    let mut devices = Vec::with_capacity(ids.len());
+    for id in ids {
+        let peripheral = central.peripheral(&id).await?;
+        let properties = peripheral.properties().await?.unwrap_or_default();
+
+        let probe = tokio::time::timeout(PROBE_TIMEOUT, probe_characteristics(&peripheral)).await;
+        let (write_characteristic, notify_characteristic, probe_error) = match probe {
+            Ok(Ok((write, notify))) => (write, notify, None),
+            Ok(Err(e)) => (false, false, Some(e.to_string())),
+            Err(_) => (false, false, Some("Timed out while connecting".to_string())),
+        };
+
+        devices.push(ScannedDevice {
+            id: id.to_string(),
+            address: peripheral.address().to_string(),
+            name: properties.local_name,
+            rssi: properties.rssi,
+            write_characteristic,
+            notify_characteristic,
+            probe_error,
+        });
+    }
+
+    Ok(devices)
+}
+
+/// When a scan gives up looking for the device. Time spent connecting to
+/// what it found moves the deadline back, so only waiting is counted.
+struct ScanDeadline {
+    deadline: Instant,
+}
+
+impl ScanDeadline {
+    fn new(timeout: Duration) -> Self {
+        ScanDeadline {
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Runs a connection attempt without counting it against the scan.
+    async fn excluding<F: Future>(&mut self, attempt: F) -> F::Output {
+        let started = Instant::now();
+        let output = attempt.await;
+        self.deadline += started.elapsed();
+        output
+    }
+}
+
+/// A vest seen during a scan.
+struct DiscoveredDevice {
+    peripheral: Peripheral,
+    id: String,
+    address: String,
+    name: Option<String>,
+}
+
+impl DiscoveredDevice {
+    /// Matches a `--device` selector or a remembered device.
+    fn matches(&self, selector: &str) -> bool {
+        if is_address(selector) {
+            self.address.eq_ignore_ascii_case(selector)
+        } else {
+            self.id == selector || self.name.as_deref() == Some(selector)
+        }
+    }
+
+    /// The most stable way to refer to this vest on the current platform;
+    /// macOS hides MAC addresses behind per-host ids.
+    fn key(&self) -> &str {
+        if self.address == "00:00:00:00:00:00" {
+            &self.id
+        } else {
+            &self.address
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct TrueGearBLEConnection {
     peripheral: Arc<Mutex<Option<Peripheral>>>,
     write_char: Arc<Mutex<Option<btleplug::api::Characteristic>>>,
     searching: Arc<Mutex<bool>>,
+    supervising: Arc<Mutex<bool>>,
+    /// Set by `disconnect` to stop the supervisor, waking it wherever it waits.
+    closing: Arc<watch::Sender<bool>>,
     on_connected: Arc<Mutex<Option<OnConnectedCallback>>>,
     on_message_received: Arc<Mutex<Option<OnMessageReceivedCallback>>>,
+    connection_state_sender: broadcast::Sender<ConnectionState>,
+    scanner: BleScanner,
+    options: BleOptions,
 }
 
 impl TrueGearBLEConnection {
-    pub fn new() -> Self {
+    pub fn new(options: BleOptions, scanner: BleScanner) -> Self {
+        let (connection_state_sender, _) = broadcast::channel(CONNECTION_STATE_CHANNEL_CAPACITY);
         TrueGearBLEConnection {
             peripheral: Arc::new(Mutex::new(None)),
             write_char: Arc::new(Mutex::new(None)),
             searching: Arc::new(Mutex::new(false)),
+            supervising: Arc::new(Mutex::new(false)),
+            closing: Arc::new(watch::Sender::new(false)),
             on_connected: Arc::new(Mutex::new(None)),
             on_message_received: Arc::new(Mutex::new(None)),
+            connection_state_sender,
+            scanner,
+            options,
         }
     }
 
-    pub async fn set_on_connected<F>(&mut self, callback: F)
-    where
-        F: Fn() + Send + Sync + 'static,
-    {
-        let mut on_connected_guard = self.on_connected.lock().await;
-        *on_connected_guard = Some(Box::new(callback));
+    fn publish_connection_state(&self, state: ConnectionState) {
+        tracing::debug!("Connection state: {:?}", state);
+        // no subscribers is fine
+        let _ = self.connection_state_sender.send(state);
     }
 
-    pub async fn set_on_message_received<F>(&mut self, callback: F)
-    where
-        F: Fn(&[u8]) + Send + Sync + 'static,
-    {
-        let mut on_message_received_guard = self.on_message_received.lock().await;
-        *on_message_received_guard = Some(Box::new(callback));
+    fn is_closing(&self) -> bool {
+        *self.closing.borrow()
     }
 
-    pub async fn start(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let _ = self.ensure_connected().await;
-        Ok(())
+    /// Resolves once `disconnect` is called.
+    async fn closed(&self) {
+        let mut closing = self.closing.subscribe();
+        // the sender lives as long as `self`
+        let _ = closing.wait_for(|closing| *closing).await;
     }
 
-    pub async fn ensure_connected(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        {
-            let connected = if let Some(peripheral) = &*self.peripheral.lock().await {
-                peripheral.is_connected().await?
-            } else {
-                false
+    async fn is_connected(&self) -> bool {
+        match &*self.peripheral.lock().await {
+            Some(peripheral) => peripheral.is_connected().await.unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Writes `data` without response, falling back to writes with response
+    /// if that fails and retries are enabled.
+    async fn write_with_retries(
+        &self,
+        peripheral: &Peripheral,
+        write_char: &btleplug::api::Characteristic,
+        data: &[u8],
+    ) -> Result<(), TrueGearError> {
+        let mut result = peripheral
+            .write(write_char, data, WriteType::WithoutResponse)
+            .await;
+        for attempt in 1..=self.options.write_retries {
+            let Err(e) = &result else {
+                break;
             };
+            tracing::warn!(
+                "Write failed ({}), retrying with response ({}/{})",
+                e,
+                attempt,
+                self.options.write_retries
+            );
+            tokio::time::sleep(self.options.write_interval).await;
+            result = peripheral
+                .write(write_char, data, WriteType::WithResponse)
+                .await;
+        }
+        Ok(result?)
+    }
 
-            if connected {
-                return Ok(());
-            }
+    pub async fn ensure_connected(&mut self) -> Result<(), TrueGearError> {
+        if self.is_connected().await {
+            return Ok(());
+        }
+
+        self.start_supervisor().await;
+
+        if *self.searching.lock().await {
+            Err(TrueGearError::Searching)
+        } else {
+            Err(TrueGearError::NotConnected)
+        }
+    }
 
-            let mut searching_guard = self.searching.lock().await;
+    /// Starts the background task that keeps the device connected, unless it
+    /// is already running.
+    async fn start_supervisor(&self) {
+        let mut supervising_guard = self.supervising.lock().await;
+        if *supervising_guard {
+            return;
+        }
+        *supervising_guard = true;
+        self.closing.send_replace(false);
 
-            if *searching_guard {
-                return Err("Searching for device".into());
+        tokio::spawn(self.clone().supervisor_loop());
+    }
+
+    /// Connects to the device, waits for the link to drop, and reconnects with
+    /// exponential backoff until `disconnect` is called or the adapter is
+    /// gone.
+    async fn supervisor_loop(self) {
+        let mut backoff = RECONNECT_BACKOFF_INITIAL;
+
+        while !self.is_closing() {
+            *self.searching.lock().await = true;
+            self.publish_connection_state(ConnectionState::Searching);
+
+            let result = self.find_and_connect().await;
+            *self.searching.lock().await = false;
+
+            if self.is_closing() {
+                // `disconnect` may have come while the device was connecting
+                if let Ok((_, peripheral)) = &result {
+                    tracing::debug!("Disconnecting from peripheral...");
+                    if let Err(e) = peripheral.disconnect().await {
+                        tracing::warn!("Failed to disconnect: {}", e);
+                    }
+                    *self.peripheral.lock().await = None;
+                    *self.write_char.lock().await = None;
+                }
+                self.publish_connection_state(ConnectionState::Disconnected);
+                break;
             }
 
-            *searching_guard = true;
+            match result {
+                Ok((central, peripheral)) => {
+                    backoff = RECONNECT_BACKOFF_INITIAL;
+                    self.publish_connection_state(ConnectionState::Connected);
+
+                    if let Some(callback) = &*self.on_connected.lock().await {
+                        callback();
+                    }
+
+                    self.watch_connection(&central, peripheral).await;
+
+                    *self.peripheral.lock().await = None;
+                    *self.write_char.lock().await = None;
+                    self.publish_connection_state(ConnectionState::Disconnected);
+
+                    if !self.is_closing() {
+                        tracing::warn!("Lost connection to device, reconnecting...");
+                    }
+                }
+                // waiting does not bring an adapter back
+                Err(e @ TrueGearError::AdapterNotFound(_)) => {
+                    self.publish_connection_state(ConnectionState::Disconnected);
+                    tracing::error!("Failed to connect: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    self.publish_connection_state(ConnectionState::Disconnected);
+                    tracing::warn!("Failed to connect: {}. Retrying in {:?}", e, backoff);
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = self.closed() => {}
+                    }
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+            }
         }
 
-        // not connected, start auto connect
-        let mut self_clone = self.clone();
-        tokio::spawn(async move {
-            let _ = self_clone.auto_connect().await;
-            *self_clone.searching.lock().await = false;
+        *self.supervising.lock().await = false;
+    }
+
+    /// Resolves once the connection to `peripheral` is lost.
+    async fn watch_connection(&self, central: &Adapter, peripheral: Peripheral) {
+        let id = peripheral.id();
+
+        let disconnect_event = async {
+            match central.events().await {
+                Ok(mut events) => {
+                    while let Some(event) = events.next().await {
+                        if let CentralEvent::DeviceDisconnected(event_id) = event
+                            && event_id == id
+                        {
+                            return;
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to watch adapter events: {}", e),
+            }
+            // rely on polling instead
+            std::future::pending::<()>().await
+        };
 
-            if let Some(callback) = &*self_clone.on_connected.lock().await {
-                callback();
+        let poll = async {
+            loop {
+                tokio::time::sleep(CONNECTION_POLL_INTERVAL).await;
+                if !peripheral.is_connected().await.unwrap_or(false) {
+                    return;
+                }
             }
-        });
-        Err("Not connected to device".into())
+        };
+
+        tokio::select! {
+            result = self.notify_loop(peripheral.clone()) => {
+                if let Err(e) = result {
+                    tracing::error!("Notification loop failed: {}", e);
+                }
+            }
+            _ = disconnect_event => tracing::debug!("Peripheral disconnected"),
+            _ = poll => tracing::debug!("Peripheral no longer connected"),
+            _ = self.closed() => tracing::debug!("Connection closed"),
+        }
     }
 
-    pub async fn auto_connect(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let manager = Manager::new().await.unwrap();
+    /// Whether a discovered device is one this connection may use.
+    fn is_target_device(&self, device: &DiscoveredDevice) -> bool {
+        match &self.options.device {
+            Some(selector) => device.matches(selector),
+            None => device
+                .name
+                .as_deref()
+                .is_some_and(|name| name.contains("Truegear_C")),
+        }
+    }
 
-        // get the first bluetooth adapter
-        // connect to the adapter
-        let adapters = manager.adapters().await?;
-        let central = adapters.into_iter().nth(0).unwrap();
+    async fn try_connect(&self, device: &DiscoveredDevice) -> bool {
+        for _ in 0..3 {
+            if self.is_closing() {
+                break;
+            }
+            match self.connect_peripheral(device.peripheral.clone()).await {
+                Ok(_) => {
+                    tracing::info!(
+                        "Successfully connected to peripheral: {} ({})",
+                        device.name.as_deref().unwrap_or("unnamed"),
+                        device.key()
+                    );
+                    return true;
+                }
+                Err(e) => {
+                    tracing::error!("Error during connection: {}", e);
+                }
+            }
+        }
+        false
+    }
 
-        let central_state = central.adapter_state().await.unwrap();
+    async fn find_and_connect(&self) -> Result<(Adapter, Peripheral), TrueGearError> {
+        let central = self.scanner.central().await?;
+
+        let central_state = central.adapter_state().await?;
         tracing::debug!("CentralState: {:?}", central_state);
 
         // Each adapter has an event stream, we fetch via events(),
@@ -106,78 +610,118 @@ impl TrueGearBLEConnection {
         // Future<Result<Stream<Item=CentralEvent>>>.
         let mut events = central.events().await?;
 
-        // start scanning for devices
-        let scan_filter = ScanFilter {
-            services: vec![SERVICE_UUID_CENTER],
-        };
-
-        central.start_scan(scan_filter.clone()).await?;
+        // start scanning for devices, together with the other connections
+        self.scanner.start_scan(&central).await?;
 
-        // Print based on whatever the event receiver outputs. Note that the event
-        // receiver blocks, so in a real program, this should be run in its own
-        // thread (not task, as this library does not yet use async channels).
-        let mut is_connected = false;
-        while let Some(event) = events.next().await {
-            if let CentralEvent::DeviceDiscovered(id) = event {
-                let peripheral = central.peripheral(&id).await?;
-                let properties = peripheral.properties().await?;
-                let name = properties
-                    .and_then(|p| p.local_name)
-                    .map(|local_name| format!("Name: {local_name}"))
-                    .unwrap_or_default();
-                tracing::debug!("DeviceDiscovered: {:?} {}", id, name);
-                if name.contains("Truegear_C") {
-                    tracing::debug!("Truegear_C device found: {:?}", name);
-                    for _ in 0..3 {
-                        match self.connect_peripheral(peripheral.clone()).await {
-                            Ok(_) => {
-                                tracing::info!("Successfully connected to peripheral: {:?}", name);
-                                is_connected = true;
-                                break;
-                            }
-                            Err(e) => {
-                                tracing::error!("Error during connection: {}", e);
-                            }
+        // an explicit --device wins over the remembered one
+        let preferred = match self.options.device {
+            Some(_) => None,
+            None => load_last_device(),
+        };
+        let grace_deadline = Instant::now() + PREFERRED_DEVICE_GRACE;
+        let mut scan_deadline = ScanDeadline::new(SCAN_TIMEOUT);
+
+        let scan = async {
+            let mut candidates = VecDeque::new();
+            let mut seen = HashSet::new();
+            loop {
+                let waiting_for_preferred = preferred.is_some() && Instant::now() < grace_deadline;
+
+                // once the remembered device had its chance, try the others
+                if !waiting_for_preferred {
+                    while let Some(device) = candidates.pop_front() {
+                        if scan_deadline.excluding(self.try_connect(&device)).await {
+                            return Ok(device);
                         }
                     }
-                    if is_connected {
-                        // start notify receiving loop
-                        let self_clone = self.clone();
-
-                        tokio::spawn(async move {
-                            let _ = self_clone.notify_loop(peripheral.clone()).await;
-                        });
+                }
 
-                        break;
+                // only the waiting for devices counts towards the scan timeout
+                let deadline = if waiting_for_preferred {
+                    grace_deadline
+                } else {
+                    scan_deadline.deadline()
+                };
+                let event = tokio::select! {
+                    event = tokio::time::timeout_at(deadline, events.next()) => event,
+                    _ = self.closed() => return Err(TrueGearError::NotConnected),
+                };
+                let event = match event {
+                    Ok(Some(event)) => event,
+                    Ok(None) => {
+                        return Err(TrueGearError::DeviceNotFound(
+                            "Scan ended before a device was found".to_string(),
+                        ));
+                    }
+                    Err(_) if waiting_for_preferred => continue,
+                    Err(_) => {
+                        return Err(TrueGearError::DeviceNotFound(match &self.options.device {
+                            Some(selector) => format!("Device {:?} not found", selector),
+                            None => "No device found".to_string(),
+                        }));
+                    }
+                };
+
+                // devices another connection's scan found first are only
+                // reported as updated
+                if let CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) = event
+                    && seen.insert(id.clone())
+                {
+                    let peripheral = central.peripheral(&id).await?;
+                    let properties = peripheral.properties().await?;
+                    let device = DiscoveredDevice {
+                        id: id.to_string(),
+                        address: peripheral.address().to_string(),
+                        name: properties.and_then(|p| p.local_name),
+                        peripheral,
+                    };
+                    tracing::debug!(
+                        "DeviceDiscovered: {:?} {} Name: {}",
+                        id,
+                        device.address,
+                        device.name.as_deref().unwrap_or_default()
+                    );
+
+                    if !self.is_target_device(&device) {
+                        continue;
+                    }
+                    tracing::debug!("Target device found: {:?}", device.name);
+
+                    if preferred
+                        .as_deref()
+                        .is_some_and(|preferred| device.matches(preferred))
+                    {
+                        if scan_deadline.excluding(self.try_connect(&device)).await {
+                            return Ok(device);
+                        }
+                    } else {
+                        candidates.push_back(device);
                     }
                 }
             }
-        }
+        };
 
-        central.stop_scan().await?;
+        let found = scan.await;
 
-        Ok(())
+        self.scanner.stop_scan(&central).await?;
+
+        let device = found?;
+        save_last_device(device.key());
+        Ok((central, device.peripheral))
     }
 
-    async fn connect_peripheral(
-        &mut self,
-        peripheral: Peripheral,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn connect_peripheral(&self, peripheral: Peripheral) -> Result<(), TrueGearError> {
         peripheral.connect().await?;
         if let Err(e) = peripheral.discover_services().await {
             peripheral.disconnect().await?;
-            return Err(format!("Failed to discover services: {}", e).into());
+            return Err(e.into());
         }
 
         let services = peripheral.services();
 
         let Some(target_service) = services.iter().find(|s| s.uuid == SERVICE_UUID_CENTER) else {
             peripheral.disconnect().await?;
-            return Err(format!(
-                "Failed to find the target BLE service {:?}",
-                SERVICE_UUID_CENTER
-            )
-            .into());
+            return Err(TrueGearError::ServiceNotFound(SERVICE_UUID_CENTER));
         };
 
         let Some(write_characteristic) = target_service
@@ -186,11 +730,9 @@ impl TrueGearBLEConnection {
             .find(|c| c.uuid == SERVICE_UUID_CENTER_WRITE_CHARACTERISTICS)
         else {
             peripheral.disconnect().await?;
-            return Err(format!(
-                "Failed to find the target BLE characteristic {:?}",
-                SERVICE_UUID_CENTER_WRITE_CHARACTERISTICS
-            )
-            .into());
+            return Err(TrueGearError::CharacteristicNotFound(
+                SERVICE_UUID_CENTER_WRITE_CHARACTERISTICS,
+            ));
         };
 
         let Some(notify_characteristic) = target_service
@@ -199,20 +741,15 @@ impl TrueGearBLEConnection {
             .find(|c| c.uuid == SERVICE_UUID_CENTER_NOTIFY_CHARACTERISTICS)
         else {
             peripheral.disconnect().await?;
-            return Err(format!(
-                "Failed to find the target BLE characteristic {:?}",
-                SERVICE_UUID_CENTER_NOTIFY_CHARACTERISTICS
-            )
-            .into());
+            return Err(TrueGearError::CharacteristicNotFound(
+                SERVICE_UUID_CENTER_NOTIFY_CHARACTERISTICS,
+            ));
         };
 
         // subscribe to notifications
         peripheral.subscribe(notify_characteristic).await?;
 
         let write_characteristic_clone = write_characteristic.clone();
-        let notify_characteristic_clone = notify_characteristic.clone();
-
-        peripheral.subscribe(&notify_characteristic_clone).await?;
 
         *self.peripheral.lock().await = Some(peripheral);
         *self.write_char.lock().await = Some(write_characteristic_clone);
@@ -220,7 +757,83 @@ impl TrueGearBLEConnection {
         Ok(())
     }
 
-    pub async fn disconnect(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn notify_loop(&self, peripheral: Peripheral) -> Result<(), TrueGearError> {
+        let mut notifications = peripheral.notifications().await?;
+        while let Some(data) = notifications.next().await {
+            tracing::debug!(
+                "Received notification on characteristic {}: {:02X?}",
+                data.uuid,
+                data.value
+            );
+            match data.uuid {
+                SERVICE_UUID_CENTER_NOTIFY_CHARACTERISTICS => {
+                    if let Some(callback) = &*self.on_message_received.lock().await {
+                        callback(&data.value);
+                    }
+                }
+                _ => {
+                    tracing::debug!(
+                        "Unhandled notification on characteristic {}: {:02X?}",
+                        data.uuid,
+                        data.value
+                    );
+                }
+            }
+        }
+        tracing::debug!("Notification stream ended");
+        Ok(())
+    }
+}
+
+impl TrueGearTransport for TrueGearBLEConnection {
+    async fn set_on_connected<F>(&mut self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let mut on_connected_guard = self.on_connected.lock().await;
+        *on_connected_guard = Some(Box::new(callback));
+    }
+
+    async fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        let mut on_message_received_guard = self.on_message_received.lock().await;
+        *on_message_received_guard = Some(Box::new(callback));
+    }
+
+    async fn start(&mut self) -> Result<(), TrueGearError> {
+        // fail straight away rather than retry without an adapter
+        self.scanner.central().await?;
+
+        self.start_supervisor().await;
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<(), TrueGearError> {
+        if self.is_connected().await {
+            return Ok(());
+        }
+
+        let mut connection_state_receiver = self.connection_state_sender.subscribe();
+        self.start_supervisor().await;
+
+        loop {
+            match connection_state_receiver.recv().await {
+                Ok(ConnectionState::Connected) => return Ok(()),
+                Ok(ConnectionState::Disconnected) => return Err(TrueGearError::NotConnected),
+                Ok(ConnectionState::Searching) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(TrueGearError::NotConnected);
+                }
+            }
+        }
+    }
+
+    async fn disconnect(&self) -> Result<(), TrueGearError> {
+        // stop the supervisor from reconnecting
+        self.closing.send_replace(true);
+
         if let Some(peripheral) = &*self.peripheral.lock().await {
             tracing::debug!("Disconnecting from peripheral...");
             peripheral.disconnect().await?;
@@ -228,52 +841,91 @@ impl TrueGearBLEConnection {
         Ok(())
     }
 
-    pub async fn send_data(&mut self, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn send_data(&mut self, data: &[u8]) -> Result<(), TrueGearError> {
         self.ensure_connected().await?;
 
-        if let (Some(peripheral), Some(write_char)) = (
-            &*self.peripheral.lock().await,
-            &*self.write_char.lock().await,
-        ) {
-            peripheral
-                .write(write_char, data, WriteType::WithoutResponse)
+        // copied out so that the paced writes do not hold up the connection
+        // state and status pushes
+        let peripheral = self.peripheral.lock().await.clone();
+        let write_char = self.write_char.lock().await.clone();
+        let (Some(peripheral), Some(write_char)) = (peripheral, write_char) else {
+            return Err(TrueGearError::NotConnected);
+        };
+
+        let writes = split_into_writes(data, self.options.max_write_size);
+        for (i, write) in writes.iter().enumerate() {
+            // give the device time to take in the previous write
+            if i > 0 {
+                tokio::time::sleep(self.options.write_interval).await;
+            }
+            self.write_with_retries(&peripheral, &write_char, write)
                 .await?;
-            Ok(())
-        } else {
-            Err("Not connected to BLE device".into())
         }
+        Ok(())
     }
 
-    async fn notify_loop(
-        &self,
-        peripheral: Peripheral,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        loop {
-            if let Some(data) = peripheral.notifications().await?.next().await {
-                tracing::debug!(
-                    "Received notification on characteristic {}: {:02X?}",
-                    data.uuid,
-                    data.value
-                );
-                match data.uuid {
-                    SERVICE_UUID_CENTER_NOTIFY_CHARACTERISTICS => {
-                        if let Some(callback) = &*self.on_message_received.lock().await {
-                            callback(&data.value);
-                        }
-                    }
-                    _ => {
-                        tracing::debug!(
-                            "Unhandled notification on characteristic {}: {:02X?}",
-                            data.uuid,
-                            data.value
-                        );
-                    }
-                }
-            } else {
-                tracing::debug!("Notification stream ended");
-                break;
-            }
+    fn max_write_size(&self) -> usize {
+        self.options.max_write_size
+    }
+
+    async fn connection_state(&self) -> ConnectionState {
+        if let Some(peripheral) = &*self.peripheral.lock().await
+            && peripheral.is_connected().await.unwrap_or(false)
+        {
+            return ConnectionState::Connected;
         }
-        Ok(())
+
+        if *self.searching.lock().await {
+            ConnectionState::Searching
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    fn subscribe_connection_state(&self) -> broadcast::Receiver<ConnectionState> {
+        self.connection_state_sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test(start_paused = true)]
+    async fn scan_deadline_does_not_count_connection_attempts() {
+        let start = Instant::now();
+        let mut scan_deadline = ScanDeadline::new(SCAN_TIMEOUT);
+
+        // the first vest turns up quickly but takes a long time to fail
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let slow_failure = async {
+            tokio::time::sleep(Duration::from_secs(7)).await;
+            false
+        };
+        assert!(!scan_deadline.excluding(slow_failure).await);
+        assert_eq!(
+            scan_deadline.deadline(),
+            start + SCAN_TIMEOUT + Duration::from_secs(7)
+        );
+
+        // the vest that advertises late is still found
+        let (events, mut received) = mpsc::channel(1);
+        let late = events.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            late.send("late vest").await.unwrap();
+        });
+        let event = tokio::time::timeout_at(scan_deadline.deadline(), received.recv()).await;
+        assert_eq!(event, Ok(Some("late vest")));
+        assert!(Instant::now() > start + SCAN_TIMEOUT);
+
+        // waiting on its own still runs out
+        let event = tokio::time::timeout_at(scan_deadline.deadline(), received.recv()).await;
+        assert!(event.is_err());
+        assert_eq!(
+            Instant::now(),
+            start + SCAN_TIMEOUT + Duration::from_secs(7)
+        );
     }
 }
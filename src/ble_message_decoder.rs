@@ -0,0 +1,380 @@
+use crate::error::TrueGearError;
+use crate::predefined;
+use crate::true_gear_message::{ActionType, Effect, IntensityMode, Track};
+
+pub const TRACK_OBJECT_LEN: usize = 16;
+
+/// A single TrackObject as it is laid out in an EffectObject.
+///
+/// See `doc/ble_protocol.md` for the byte layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackObject {
+    pub action_type: ActionType,
+    pub fade: bool,
+    pub keep: bool,
+    pub once: bool,
+    pub register_id: u8,
+    pub start_time: u16,
+    pub end_time: u16,
+    pub interval: u8,
+    pub start_intensity: u16,
+    pub end_intensity: u16,
+    pub index: Vec<u8>,
+}
+
+impl TrackObject {
+    pub fn read_ble_bytes_from(object: &[u8]) -> Result<Self, TrueGearError> {
+        if object.len() != TRACK_OBJECT_LEN {
+            return Err(TrueGearError::Protocol(
+                "Incomplete TrackObject".to_string(),
+            ));
+        }
+
+        let read_u16 = |at: usize| ((object[at] as u16) << 8) | object[at + 1] as u16;
+
+        match object[0] {
+            0x01..=0x04 => {
+                let mut index = Vec::new();
+                for (&i, &shift) in predefined::shake_flag_shift_map() {
+                    let byte_index = 8 + (8 - 1 - (shift / 8)) as usize; // big endian
+                    if object[byte_index] & (1 << (shift % 8)) != 0 {
+                        index.push(i);
+                    }
+                }
+                index.sort_unstable();
+
+                Ok(TrackObject {
+                    action_type: ActionType::Shake,
+                    fade: matches!(object[0], 0x02 | 0x04),
+                    keep: matches!(object[0], 0x03 | 0x04),
+                    once: false,
+                    register_id: object[1],
+                    start_time: read_u16(2),
+                    end_time: read_u16(4),
+                    interval: 0,
+                    start_intensity: object[6] as u16,
+                    end_intensity: object[7] as u16,
+                    index,
+                })
+            }
+            0x10..=0x12 => {
+                let mut index = Vec::new();
+                for (&i, &shifts) in predefined::electrical_flag_shift_map() {
+                    let enabled = shifts.iter().any(|&shift| {
+                        let byte_index = 12 + (4 - 1 - (shift / 8)) as usize; // big endian
+                        object[byte_index] & (1 << (shift % 8)) != 0
+                    });
+                    if enabled {
+                        index.push(i);
+                    }
+                }
+                index.sort_unstable();
+
+                Ok(TrackObject {
+                    action_type: ActionType::Electrical,
+                    fade: object[0] == 0x12,
+                    keep: false,
+                    once: object[0] == 0x10,
+                    register_id: 0,
+                    start_time: read_u16(2),
+                    end_time: read_u16(4),
+                    interval: object[6],
+                    start_intensity: read_u16(8),
+                    end_intensity: read_u16(10),
+                    index,
+                })
+            }
+            other => Err(TrueGearError::Protocol(format!(
+                "Unknown TrackObject type: {:02X}",
+                other
+            ))),
+        }
+    }
+
+    /// Whether `next` is the falling half that the encoder emits after `self`
+    /// for a FadeInAndOut track.
+    pub fn is_fade_in_and_out_pair(&self, next: &TrackObject) -> bool {
+        self.fade
+            && next.fade
+            && self.action_type == next.action_type
+            && self.keep == next.keep
+            && self.once == next.once
+            && self.interval == next.interval
+            && self.index == next.index
+            && self.end_time == next.start_time
+            && self.start_intensity == next.end_intensity
+            && self.end_intensity == next.start_intensity
+    }
+}
+
+/// Splits a buffer of concatenated `68 68 N ... 16` EffectObjects into their
+/// TrackObjects.
+pub fn read_effect_objects(data: &[u8]) -> Result<Vec<Vec<TrackObject>>, TrueGearError> {
+    let mut effect_objects = Vec::new();
+
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (header, body) = rest
+            .split_at_checked(3)
+            .ok_or_else(|| TrueGearError::Protocol("Incomplete EffectObject header".to_string()))?;
+        if header[..2] != [0x68, 0x68] {
+            return Err(TrueGearError::Protocol(format!(
+                "Unknown EffectObject header: {:02X?}",
+                &header[..2]
+            )));
+        }
+
+        let num_tracks = header[2] as usize;
+        let (objects, tail) = body
+            .split_at_checked(num_tracks * TRACK_OBJECT_LEN)
+            .ok_or_else(|| TrueGearError::Protocol("Incomplete EffectObject".to_string()))?;
+        let (&end, tail) = tail.split_first().ok_or_else(|| {
+            TrueGearError::Protocol("Missing EffectObject terminator".to_string())
+        })?;
+        if end != 0x16 {
+            return Err(TrueGearError::Protocol(format!(
+                "Unknown EffectObject terminator: {:02X}",
+                end
+            )));
+        }
+
+        effect_objects.push(
+            objects
+                .chunks_exact(TRACK_OBJECT_LEN)
+                .map(TrackObject::read_ble_bytes_from)
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+
+        rest = tail;
+    }
+
+    Ok(effect_objects)
+}
+
+impl Effect {
+    /// Decodes every EffectObject in `data` back into an `Effect`.
+    ///
+    /// Electrical intensities are divided by `electical_effect_ratio` to undo
+    /// the scaling applied by `write_ble_bytes_to`. Names are not sent to the
+    /// device, so decoded effects are named "Decoded".
+    pub fn read_ble_bytes_from(
+        data: &[u8],
+        electical_effect_ratio: f32,
+    ) -> Result<Vec<Effect>, TrueGearError> {
+        let unscale = |intensity: u16| {
+            if electical_effect_ratio > 0.0 {
+                ((intensity as f32) / electical_effect_ratio).round() as u16
+            } else {
+                intensity
+            }
+        };
+
+        let mut effects = Vec::new();
+
+        for track_objects in read_effect_objects(data)? {
+            let mut tracks = Vec::new();
+            let mut objects = track_objects.iter().peekable();
+
+            while let Some(object) = objects.next() {
+                let (end_time, intensity_mode) = match objects.peek() {
+                    Some(next) if object.is_fade_in_and_out_pair(next) => {
+                        let end_time = next.end_time;
+                        objects.next();
+                        (end_time, IntensityMode::FadeInAndOut)
+                    }
+                    _ if object.fade => (object.end_time, IntensityMode::Fade),
+                    // Once mode has no dedicated fade flag on the wire
+                    _ if object.once && object.start_intensity != object.end_intensity => {
+                        (object.end_time, IntensityMode::Fade)
+                    }
+                    _ => (object.end_time, IntensityMode::Const),
+                };
+
+                let (start_intensity, end_intensity) = match object.action_type {
+                    ActionType::Shake => (object.start_intensity, object.end_intensity),
+                    ActionType::Electrical => (
+                        unscale(object.start_intensity),
+                        unscale(object.end_intensity),
+                    ),
+                };
+
+                tracks.push(Track {
+                    start_time: object.start_time.into(),
+                    end_time: end_time.into(),
+                    stop_name: "".into(),
+                    start_intensity,
+                    end_intensity,
+                    intensity_mode,
+                    action_type: object.action_type.clone(),
+                    once: object.once,
+                    interval: object.interval,
+                    index: object.index.clone(),
+                });
+            }
+
+            effects.push(Effect {
+                name: "Decoded".into(),
+                uuid: "Decoded".into(),
+                keep: track_objects.iter().any(|object| object.keep),
+                priority: 0,
+                tracks,
+                repeat: 1,
+            });
+        }
+
+        Ok(effects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(action_type: ActionType, intensity_mode: IntensityMode, index: Vec<u8>) -> Track {
+        Track {
+            start_time: 100,
+            end_time: 601,
+            stop_name: "".into(),
+            start_intensity: 10,
+            end_intensity: 80,
+            intensity_mode,
+            action_type,
+            once: false,
+            interval: 0,
+            index,
+        }
+    }
+
+    fn effect(keep: bool, tracks: Vec<Track>) -> Effect {
+        Effect {
+            name: "Decoded".into(),
+            uuid: "Decoded".into(),
+            keep,
+            priority: 0,
+            tracks,
+            repeat: 1,
+        }
+    }
+
+    fn round_trip(effect: &Effect, electical_effect_ratio: f32) -> Vec<Effect> {
+        let mut buffer = Vec::new();
+        effect
+            .write_ble_bytes_to(&mut buffer, electical_effect_ratio)
+            .unwrap();
+        Effect::read_ble_bytes_from(&buffer, electical_effect_ratio).unwrap()
+    }
+
+    #[test]
+    fn round_trips_shake_tracks() {
+        let effect = effect(
+            false,
+            vec![
+                Track {
+                    end_intensity: 10,
+                    ..track(ActionType::Shake, IntensityMode::Const, vec![0, 1, 104])
+                },
+                track(ActionType::Shake, IntensityMode::Fade, vec![4, 119]),
+                track(ActionType::Shake, IntensityMode::FadeInAndOut, vec![8]),
+            ],
+        );
+        assert_eq!(round_trip(&effect, 1.0), [effect]);
+    }
+
+    #[test]
+    fn round_trips_keep() {
+        let effect = effect(
+            true,
+            vec![
+                Track {
+                    end_intensity: 10,
+                    ..track(ActionType::Shake, IntensityMode::Const, vec![0])
+                },
+                track(ActionType::Shake, IntensityMode::FadeInAndOut, vec![1]),
+            ],
+        );
+        let decoded = round_trip(&effect, 1.0);
+        assert_eq!(decoded, [effect]);
+    }
+
+    #[test]
+    fn round_trips_electrical_tracks() {
+        let effect = effect(
+            false,
+            vec![
+                Track {
+                    end_intensity: 10,
+                    interval: 20,
+                    ..track(ActionType::Electrical, IntensityMode::Const, vec![0, 100])
+                },
+                Track {
+                    interval: 5,
+                    ..track(ActionType::Electrical, IntensityMode::Fade, vec![0])
+                },
+                track(
+                    ActionType::Electrical,
+                    IntensityMode::FadeInAndOut,
+                    vec![100],
+                ),
+                Track {
+                    once: true,
+                    end_intensity: 10,
+                    ..track(ActionType::Electrical, IntensityMode::Const, vec![100])
+                },
+            ],
+        );
+        // intensities are scaled on the wire and scaled back when decoded
+        assert_eq!(round_trip(&effect, 0.5), [effect]);
+    }
+
+    #[test]
+    fn round_trips_mixed_tracks() {
+        let effect = effect(
+            false,
+            vec![
+                track(ActionType::Shake, IntensityMode::FadeInAndOut, vec![0, 4]),
+                track(ActionType::Electrical, IntensityMode::FadeInAndOut, vec![0]),
+                track(ActionType::Shake, IntensityMode::Fade, vec![4]),
+            ],
+        );
+        assert_eq!(round_trip(&effect, 1.0), [effect]);
+    }
+
+    #[test]
+    fn decodes_each_effect_object() {
+        let first = effect(
+            false,
+            vec![track(ActionType::Shake, IntensityMode::Fade, vec![0])],
+        );
+        let second = effect(
+            true,
+            vec![track(
+                ActionType::Shake,
+                IntensityMode::FadeInAndOut,
+                vec![1],
+            )],
+        );
+
+        let mut buffer = Vec::new();
+        first.write_ble_bytes_to(&mut buffer, 1.0).unwrap();
+        second.write_ble_bytes_to(&mut buffer, 1.0).unwrap();
+
+        assert_eq!(read_effect_objects(&buffer).unwrap().len(), 2);
+        assert_eq!(
+            Effect::read_ble_bytes_from(&buffer, 1.0).unwrap(),
+            [first, second]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let effect = effect(
+            false,
+            vec![track(ActionType::Shake, IntensityMode::Fade, vec![0])],
+        );
+        let mut buffer = Vec::new();
+        effect.write_ble_bytes_to(&mut buffer, 1.0).unwrap();
+
+        assert!(Effect::read_ble_bytes_from(&buffer[..buffer.len() - 1], 1.0).is_err());
+        assert!(Effect::read_ble_bytes_from(&buffer[..2], 1.0).is_err());
+    }
+}
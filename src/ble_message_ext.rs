@@ -1,5 +1,116 @@
+use crate::ble_message_decoder::{TRACK_OBJECT_LEN, TrackObject};
+use crate::error::TrueGearError;
 use crate::true_gear_message;
-use std::error::Error;
+
+/// The register_id of every shake TrackObject. How the device uses it is not
+/// known, and the official player writes 0 for effects that are not kept.
+/// Keep effects are written with 0 as well: they are ended by keep frames at
+/// zero intensity (see `controller::stop_effect`), which would leave them
+/// running if the device kept held tracks apart by register_id.
+const REGISTER_ID: u8 = 0x00;
+
+/// Splits concatenated EffectObjects into writes of at most `max_size` bytes.
+///
+/// EffectObjects are packed whole into a write while they fit. One too large
+/// for a write of its own is split between its tracks into several
+/// EffectObjects, keeping the two TrackObjects of a FadeInAndOut track
+/// together. If `data` is not a sequence of EffectObjects it is returned as a
+/// single write.
+pub fn split_into_writes(data: &[u8], max_size: usize) -> Vec<Vec<u8>> {
+    // header and terminator around the TrackObjects
+    const EFFECT_OBJECT_OVERHEAD: usize = 4;
+    let tracks_per_write = (max_size.saturating_sub(EFFECT_OBJECT_OVERHEAD) / TRACK_OBJECT_LEN)
+        .clamp(1, u8::MAX as usize);
+
+    let mut effect_objects: Vec<Vec<u8>> = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some(&[0x68, 0x68, num_tracks]) = rest.get(..3) else {
+            return vec![data.to_vec()];
+        };
+        let len = num_tracks as usize * TRACK_OBJECT_LEN + EFFECT_OBJECT_OVERHEAD;
+        let Some((effect_object, tail)) = rest.split_at_checked(len) else {
+            return vec![data.to_vec()];
+        };
+        if effect_object[len - 1] != 0x16 {
+            return vec![data.to_vec()];
+        }
+
+        if len <= max_size {
+            effect_objects.push(effect_object.to_vec());
+        } else {
+            let mut parts: Vec<Vec<u8>> = Vec::new();
+            for track in split_tracks(&effect_object[3..len - 1]) {
+                let count = track.len() / TRACK_OBJECT_LEN;
+                match parts.last_mut() {
+                    Some(part) if part[2] as usize + count <= tracks_per_write => {
+                        part.extend(track);
+                        part[2] += count as u8;
+                    }
+                    _ => {
+                        let mut part = vec![0x68, 0x68, count as u8];
+                        part.extend(track);
+                        parts.push(part);
+                    }
+                }
+            }
+            for mut part in parts {
+                part.push(0x16);
+                effect_objects.push(part);
+            }
+        }
+
+        rest = tail;
+    }
+
+    let mut writes: Vec<Vec<u8>> = Vec::new();
+    for effect_object in effect_objects {
+        match writes.last_mut() {
+            Some(write) if write.len() + effect_object.len() <= max_size => {
+                write.extend(effect_object);
+            }
+            _ => writes.push(effect_object),
+        }
+    }
+    writes
+}
+
+/// Fails if `data` cannot be split into writes of at most `max_size` bytes,
+/// which happens when a single track does not fit one: a FadeInAndOut track
+/// needs 36 bytes, more than the 20 of the smallest MTU.
+pub fn check_fits_writes(data: &[u8], max_size: usize) -> Result<(), TrueGearError> {
+    match split_into_writes(data, max_size).iter().map(Vec::len).max() {
+        Some(len) if len > max_size => Err(TrueGearError::Encode(format!(
+            "a track needs a write of {} bytes, but the link takes at most {} (see --ble-mtu)",
+            len, max_size
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Splits the TrackObjects of an EffectObject into the tracks they encode:
+/// one TrackObject each, or two for a FadeInAndOut track.
+fn split_tracks(objects: &[u8]) -> Vec<&[u8]> {
+    let parsed: Vec<Option<TrackObject>> = objects
+        .chunks(TRACK_OBJECT_LEN)
+        .map(|object| TrackObject::read_ble_bytes_from(object).ok())
+        .collect();
+
+    let mut tracks = Vec::new();
+    let mut position = 0;
+    while position < parsed.len() {
+        let paired = match (&parsed[position], parsed.get(position + 1)) {
+            (Some(object), Some(Some(next))) => object.is_fade_in_and_out_pair(next),
+            _ => false,
+        };
+        let count = if paired { 2 } else { 1 };
+        let start = position * TRACK_OBJECT_LEN;
+        let end = (start + count * TRACK_OBJECT_LEN).min(objects.len());
+        tracks.push(&objects[start..end]);
+        position += count;
+    }
+    tracks
+}
 
 enum IntensityModeSingleTrack {
     Const,
@@ -7,11 +118,12 @@ enum IntensityModeSingleTrack {
 }
 
 impl true_gear_message::Message {
+    #[allow(dead_code)]
     pub fn write_ble_bytes_to<'a>(
         &self,
         buffer: &'a mut Vec<u8>,
         electical_effect_ratio: f32,
-    ) -> Result<&'a Vec<u8>, Box<dyn Error + Send + Sync>> {
+    ) -> Result<&'a Vec<u8>, TrueGearError> {
         self.body.write_ble_bytes_to(buffer, electical_effect_ratio)
     }
 }
@@ -21,17 +133,34 @@ impl true_gear_message::Effect {
         &self,
         buffer: &'a mut Vec<u8>,
         electical_effect_ratio: f32,
-    ) -> Result<&'a Vec<u8>, Box<dyn Error + Send + Sync>> {
-        // Serialize the command body into bytes suitable for BLE transmission
+    ) -> Result<&'a Vec<u8>, TrueGearError> {
+        self.validate()?;
+
+        // Serialize the command body into bytes suitable for BLE transmission.
+        // The count of TrackObjects is a single byte, so the tracks are spread
+        // over as many EffectObjects as needed.
+        let mut header = buffer.len();
         buffer.extend([0x68, 0x68, 0x00]);
 
         for track in &self.tracks {
-            track.write_ble_bytes_to(
-                buffer,
-                self.keep,
-                self.uuid.clone(),
-                electical_effect_ratio,
-            )?;
+            let mut track_objects = Vec::new();
+            track.write_ble_bytes_to(&mut track_objects, self.keep, electical_effect_ratio)?;
+            let count = track_objects.len() / TRACK_OBJECT_LEN;
+
+            if buffer[header + 2] as usize + count > u8::MAX as usize {
+                buffer.push(0x16);
+                header = buffer.len();
+                buffer.extend([0x68, 0x68, 0x00]);
+            }
+            let count = u8::try_from(count).map_err(|_| {
+                TrueGearError::Encode(format!(
+                    "a track encodes to {} TrackObjects, more than an EffectObject holds",
+                    count
+                ))
+            })?;
+
+            buffer.extend(track_objects);
+            buffer[header + 2] += count;
         }
 
         buffer.push(0x16);
@@ -41,6 +170,12 @@ impl true_gear_message::Effect {
 }
 
 impl true_gear_message::Track {
+    /// Where a FadeInAndOut track turns from fading in to fading out.
+    pub fn fade_in_and_out_midpoint(&self) -> u32 {
+        // summed in u64 so late tracks do not overflow
+        ((self.start_time as u64 + self.end_time as u64) / 2) as u32
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn write_ble_track_object_shake(
         buffer: &mut Vec<u8>,
@@ -52,7 +187,7 @@ impl true_gear_message::Track {
         intensity_start: u16,
         intensity_end: u16,
         index: &[u8],
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    ) -> Result<(), TrueGearError> {
         match (intensity_mode, keep) {
             (IntensityModeSingleTrack::Const, false) => {
                 buffer.push(0x01);
@@ -117,7 +252,7 @@ impl true_gear_message::Track {
         intensity_end: u16,
         index: &[u8],
         electical_effect_ratio: f32,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    ) -> Result<(), TrueGearError> {
         match (intensity_mode, once) {
             (_, true) => {
                 buffer.push(0x10);
@@ -176,13 +311,27 @@ impl true_gear_message::Track {
         &self,
         buffer: &mut Vec<u8>,
         keep: bool,
-        _uuid: String,
         electical_effect_ratio: f32,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    ) -> Result<(), TrueGearError> {
         let action_type = self.action_type.clone();
         let intensity_mode = self.intensity_mode.clone();
         let once = self.once;
 
+        // longer effects are played by the controller's scheduler, one
+        // window at a time
+        let time = |time: u32| {
+            u16::try_from(time).map_err(|_| {
+                TrueGearError::Encode(format!(
+                    "{} ms is beyond the maximum of {} ms a frame holds",
+                    time,
+                    u16::MAX
+                ))
+            })
+        };
+        let start_time = time(self.start_time)?;
+        let end_time = time(self.end_time)?;
+        let midpoint = time(self.fade_in_and_out_midpoint())?;
+
         match action_type {
             true_gear_message::ActionType::Shake => {
                 true_gear_message::Track::write_ble_track_object_shake(
@@ -194,14 +343,12 @@ impl true_gear_message::Track {
                             IntensityModeSingleTrack::Fade
                         }
                     },
-                    0x00, // TODO: uuid to id mapping
+                    REGISTER_ID,
                     keep,
-                    self.start_time,
+                    start_time,
                     match intensity_mode {
-                        true_gear_message::IntensityMode::FadeInAndOut => {
-                            (self.start_time + self.end_time) / 2
-                        }
-                        _ => self.end_time,
+                        true_gear_message::IntensityMode::FadeInAndOut => midpoint,
+                        _ => end_time,
                     },
                     self.start_intensity,
                     match intensity_mode {
@@ -210,21 +357,19 @@ impl true_gear_message::Track {
                     },
                     &self.index,
                 )?;
-                buffer[2] += 1;
 
                 if let true_gear_message::IntensityMode::FadeInAndOut = intensity_mode {
                     true_gear_message::Track::write_ble_track_object_shake(
                         buffer,
                         IntensityModeSingleTrack::Fade,
-                        0x00, // TODO: uuid to id mapping
+                        REGISTER_ID,
                         keep,
-                        (self.start_time + self.end_time) / 2,
-                        self.end_time,
+                        midpoint,
+                        end_time,
                         self.end_intensity,
                         self.start_intensity,
                         &self.index,
                     )?;
-                    buffer[2] += 1;
                 }
             }
             true_gear_message::ActionType::Electrical => {
@@ -238,12 +383,10 @@ impl true_gear_message::Track {
                         }
                     },
                     once,
-                    self.start_time,
+                    start_time,
                     match intensity_mode {
-                        true_gear_message::IntensityMode::FadeInAndOut => {
-                            (self.start_time + self.end_time) / 2
-                        }
-                        _ => self.end_time,
+                        true_gear_message::IntensityMode::FadeInAndOut => midpoint,
+                        _ => end_time,
                     },
                     self.interval,
                     self.start_intensity,
@@ -254,25 +397,170 @@ impl true_gear_message::Track {
                     &self.index,
                     electical_effect_ratio,
                 )?;
-                buffer[2] += 1;
 
                 if let true_gear_message::IntensityMode::FadeInAndOut = intensity_mode {
                     true_gear_message::Track::write_ble_track_object_electrical(
                         buffer,
                         IntensityModeSingleTrack::Fade,
                         once,
-                        (self.start_time + self.end_time) / 2,
-                        self.end_time,
+                        midpoint,
+                        end_time,
                         self.interval,
                         self.end_intensity,
                         self.start_intensity,
                         &self.index,
                         electical_effect_ratio,
                     )?;
-                    buffer[2] += 1;
                 }
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble_message_decoder::read_effect_objects;
+    use crate::true_gear_message::{ActionType, Effect, IntensityMode, Track};
+
+    fn track(intensity_mode: IntensityMode, index: u8) -> Track {
+        Track {
+            start_time: 0,
+            end_time: 200,
+            stop_name: "".into(),
+            start_intensity: 10,
+            // a Const track is sent with its start intensity only
+            end_intensity: match intensity_mode {
+                IntensityMode::Const => 10,
+                _ => 60,
+            },
+            intensity_mode,
+            action_type: ActionType::Shake,
+            once: false,
+            interval: 0,
+            index: vec![index],
+        }
+    }
+
+    fn effect(tracks: Vec<Track>) -> Effect {
+        Effect {
+            name: "Decoded".into(),
+            uuid: "Decoded".into(),
+            keep: false,
+            priority: 0,
+            tracks,
+            repeat: 1,
+        }
+    }
+
+    fn encode(effect: &Effect) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        effect.write_ble_bytes_to(&mut buffer, 1.0).unwrap();
+        buffer
+    }
+
+    /// The tracks of every EffectObject in `writes`, in order.
+    fn decoded_tracks(writes: &[Vec<u8>]) -> Vec<Track> {
+        writes
+            .iter()
+            .flat_map(|write| Effect::read_ble_bytes_from(write, 1.0).unwrap())
+            .flat_map(|effect| effect.tracks)
+            .collect()
+    }
+
+    #[test]
+    fn packs_whole_effect_objects() {
+        let first = encode(&effect(vec![track(IntensityMode::Const, 0)]));
+        let second = encode(&effect(vec![track(IntensityMode::Fade, 1)]));
+        let third = encode(&effect(vec![track(IntensityMode::Fade, 4)]));
+        let data = [first.clone(), second.clone(), third.clone()].concat();
+
+        assert_eq!(first.len(), 20);
+        assert_eq!(
+            split_into_writes(&data, 50),
+            [[first, second].concat(), third.clone()]
+        );
+        assert_eq!(split_into_writes(&data, 244), [data]);
+    }
+
+    #[test]
+    fn splits_oversized_effect_object_between_tracks() {
+        let mut tracks = vec![track(IntensityMode::Const, 0)];
+        tracks.extend((1..=10).map(|index| track(IntensityMode::FadeInAndOut, index)));
+        let effect = effect(tracks);
+        let data = encode(&effect);
+        assert_eq!(data.len(), 21 * TRACK_OBJECT_LEN + 4);
+
+        // 15 TrackObjects fit a write, which would split the last pair
+        let writes = split_into_writes(&data, 244);
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0][2], 15);
+        assert_eq!(writes[1][2], 6);
+        assert!(writes.iter().all(|write| write.len() <= 244));
+        assert_eq!(decoded_tracks(&writes), effect.tracks);
+
+        // one more TrackObject in the first part would have to be half a pair
+        let writes = split_into_writes(&data, 16 * TRACK_OBJECT_LEN + 4);
+        assert_eq!(writes[0][2], 15);
+        assert_eq!(decoded_tracks(&writes), effect.tracks);
+    }
+
+    #[test]
+    fn spreads_tracks_over_effect_objects() {
+        // the FadeInAndOut track after 254 TrackObjects does not fit the first
+        // EffectObject and moves whole to the next
+        let mut tracks: Vec<Track> = (0..254)
+            .map(|position| track(IntensityMode::Const, [0, 1, 4][position % 3]))
+            .collect();
+        tracks.push(track(IntensityMode::FadeInAndOut, 8));
+        tracks.extend((0..20).map(|_| track(IntensityMode::Fade, 12)));
+        let effect = effect(tracks);
+        let data = encode(&effect);
+
+        let counts: Vec<usize> = read_effect_objects(&data)
+            .unwrap()
+            .iter()
+            .map(Vec::len)
+            .collect();
+        assert_eq!(counts, [254, 22]);
+        assert_eq!(decoded_tracks(std::slice::from_ref(&data)), effect.tracks);
+
+        let writes = split_into_writes(&data, 244);
+        assert!(writes.iter().all(|write| write.len() <= 244));
+        assert_eq!(decoded_tracks(&writes), effect.tracks);
+    }
+
+    #[test]
+    fn rejects_tracks_larger_than_a_write() {
+        let constant = encode(&effect(vec![track(IntensityMode::Const, 0)]));
+        let fade = encode(&effect(vec![
+            track(IntensityMode::Const, 0),
+            track(IntensityMode::FadeInAndOut, 1),
+        ]));
+
+        // the 20 bytes a 23-byte MTU leaves take a Const track, not a fade
+        assert!(check_fits_writes(&constant, 20).is_ok());
+        assert_eq!(
+            split_into_writes(&fade, 20)[1].len(),
+            2 * TRACK_OBJECT_LEN + 4
+        );
+        assert!(matches!(
+            check_fits_writes(&fade, 20),
+            Err(TrueGearError::Encode(message))
+                if message == "a track needs a write of 36 bytes, but the link takes at most 20 (see --ble-mtu)"
+        ));
+        assert!(check_fits_writes(&fade, 35).is_err());
+        assert!(check_fits_writes(&fade, 36).is_ok());
+    }
+
+    #[test]
+    fn passes_other_data_through() {
+        let data = vec![0x01, 0x02, 0x03];
+        assert_eq!(split_into_writes(&data, 2), [data]);
+
+        let mut data = encode(&effect(vec![track(IntensityMode::Const, 0)]));
+        *data.last_mut().unwrap() = 0x00;
+        assert_eq!(split_into_writes(&data, 10), [data]);
+    }
+}
@@ -1,7 +1,8 @@
-use std::error::Error;
-
-#[derive(Clone)]
-pub struct BleNotifyParser {}
+use crate::ble_message_decoder::TrackObject;
+use crate::error::TrueGearError;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
 
 const BATTARY_FULL: f32 = 4200_f32;
 const BATTARY_EMPTY: f32 = 3400_f32;
@@ -10,58 +11,208 @@ const K: f32 = 12.0;
 // Midpoint adjustment
 const M: f32 = 0.5;
 
-struct DeviceStatus {
-    main_model: u16,
-    main_battery_mv: u16,
-    left_model: u16,
-    left_battery_mv: u16,
-    right_model: u16,
-    right_battery_mv: u16,
+const FRAME_HEADER: [u8; 2] = [0x68, 0x68];
+const FRAME_END: u8 = 0x16;
+const OBJECT_LEN: usize = 16;
+const STATUS_CHANNEL_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceStatus {
+    pub main_model: u16,
+    pub main_battery_mv: u16,
+    pub left_model: u16,
+    pub left_battery_mv: u16,
+    pub right_model: u16,
+    pub right_battery_mv: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModuleState {
+    Online,
+    /// The vest reports a detached module and a charging module the same
+    /// way (0 mV), so the two cannot be told apart.
+    DetachedOrCharging,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ModuleStatus {
+    pub model: u16,
+    pub battery_mv: u16,
+    pub battery_percent: u8,
+    pub state: ModuleState,
+}
+
+/// Published whenever the device reports its status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DeviceStatusEvent {
+    pub main: ModuleStatus,
+    pub left: ModuleStatus,
+    pub right: ModuleStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyObject {
+    /// A shake or electrical TrackObject echoed by the device.
+    Track(TrackObject),
+    DeviceStatus(DeviceStatus),
+    Unknown(Vec<u8>),
+}
+
+fn is_known_object_type(obj_type: u8) -> bool {
+    matches!(obj_type, 0x01..=0x04 | 0x10..=0x12 | 0x81)
+}
+
+/// A complete `68 68 N ... 16` frame received from the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyFrame {
+    pub objects: Vec<NotifyObject>,
+}
+
+/// Reassembles notify frames from BLE notifications.
+///
+/// Frames may be split across notifications or preceded by garbage; partial
+/// frames are buffered until the rest arrives.
+#[derive(Clone)]
+pub struct BleNotifyParser {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    device_status: Arc<Mutex<Option<DeviceStatusEvent>>>,
+    device_status_sender: broadcast::Sender<DeviceStatusEvent>,
 }
 
 impl BleNotifyParser {
     pub fn new() -> Self {
-        BleNotifyParser {}
+        let (device_status_sender, _) = broadcast::channel(STATUS_CHANNEL_CAPACITY);
+        BleNotifyParser {
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            device_status: Arc::new(Mutex::new(None)),
+            device_status_sender,
+        }
     }
 
-    pub fn on_message_received(&self, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pub fn subscribe_device_status(&self) -> broadcast::Receiver<DeviceStatusEvent> {
+        self.device_status_sender.subscribe()
+    }
+
+    /// The most recent status reported by the device, if any.
+    pub fn device_status(&self) -> Option<DeviceStatusEvent> {
+        *self.device_status.lock().unwrap()
+    }
+
+    /// Feeds a notification into the parser and returns every frame it
+    /// completes.
+    pub fn on_message_received(&self, data: &[u8]) -> Vec<NotifyFrame> {
         tracing::debug!("Received data from TrueGear: {:02X?}", data);
 
-        fn consume_bytes<'a>(
-            iter: &mut std::slice::Iter<'a, u8>,
-            bytes: &[u8],
-        ) -> Result<(), Box<dyn Error + Send + Sync>> {
-            for byte in bytes.iter() {
-                match iter.next() {
-                    Some(&b) if b == *byte => {}
-                    Some(_) => return Err("Unknown data received from TrueGear".into()),
-                    None => return Err("Incomplete data received from TrueGear".into()),
-                }
-            }
-            Ok(())
-        }
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend_from_slice(data);
 
-        let mut data_iter = data.iter();
+        let mut frames = Vec::new();
 
         loop {
-            consume_bytes(&mut data_iter, &[0x68, 0x68])?;
-
-            match data_iter.next() {
-                Some(&num_objects) => {
-                    for _ in 0..num_objects {
-                        self.parse_notify_object(&mut data_iter)?;
-                    }
+            // resynchronize on the frame header
+            let Some(start) = buffer.windows(2).position(|w| w == FRAME_HEADER) else {
+                // keep a trailing 0x68 that may be the first half of a header
+                let keep = usize::from(buffer.last() == Some(&FRAME_HEADER[0]));
+                let skipped = buffer.len() - keep;
+                if skipped > 0 {
+                    tracing::warn!(
+                        "Discarding unknown data from TrueGear: {:02X?}",
+                        &buffer[..skipped]
+                    );
+                    buffer.drain(..skipped);
                 }
-                None => {
-                    tracing::error!("Incomplete data received from TrueGear: {:02X?}", data);
+                break;
+            };
+
+            if start > 0 {
+                tracing::warn!(
+                    "Discarding unknown data from TrueGear: {:02X?}",
+                    &buffer[..start]
+                );
+                buffer.drain(..start);
+            }
+
+            let Some(&num_objects) = buffer.get(2) else {
+                break;
+            };
+
+            let frame_len = 3 + num_objects as usize * OBJECT_LEN + 1;
+            if buffer.len() < frame_len {
+                // a header followed by unknown object types is garbage rather
+                // than the start of a frame that is still arriving
+                let plausible = buffer[3..]
+                    .iter()
+                    .step_by(OBJECT_LEN)
+                    .all(|&obj_type| is_known_object_type(obj_type));
+                if plausible {
+                    // wait for the rest of the frame
                     break;
                 }
+                buffer.drain(..1);
+                continue;
+            }
+
+            if buffer[frame_len - 1] != FRAME_END {
+                // not a real header, skip it and search again
+                tracing::warn!(
+                    "Malformed frame from TrueGear: {:02X?}",
+                    &buffer[..frame_len]
+                );
+                buffer.drain(..1);
+                continue;
             }
 
-            consume_bytes(&mut data_iter, &[0x16])?;
+            let frame: Vec<u8> = buffer.drain(..frame_len).collect();
+            match self.parse_frame(&frame) {
+                Ok(frame) => frames.push(frame),
+                Err(e) => tracing::error!("Failed to parse frame from TrueGear: {}", e),
+            }
+        }
+
+        drop(buffer);
+
+        for frame in &frames {
+            for object in &frame.objects {
+                if let NotifyObject::DeviceStatus(device_status) = object {
+                    self.publish_device_status(device_status);
+                }
+            }
         }
 
-        Ok(())
+        frames
+    }
+
+    fn publish_device_status(&self, device_status: &DeviceStatus) {
+        let module_status = |model: u16, battery_mv: u16| ModuleStatus {
+            model,
+            battery_mv,
+            battery_percent: (self.parse_battery_level(battery_mv) * 100.0).round() as u8,
+            state: if battery_mv == 0 {
+                ModuleState::DetachedOrCharging
+            } else {
+                ModuleState::Online
+            },
+        };
+
+        let event = DeviceStatusEvent {
+            main: module_status(device_status.main_model, device_status.main_battery_mv),
+            left: module_status(device_status.left_model, device_status.left_battery_mv),
+            right: module_status(device_status.right_model, device_status.right_battery_mv),
+        };
+
+        *self.device_status.lock().unwrap() = Some(event);
+        // no subscribers is fine
+        let _ = self.device_status_sender.send(event);
+    }
+
+    fn parse_frame(&self, frame: &[u8]) -> Result<NotifyFrame, TrueGearError> {
+        let objects = frame[3..frame.len() - 1]
+            .chunks_exact(OBJECT_LEN)
+            .map(|object| self.parse_notify_object(object))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(NotifyFrame { objects })
     }
 
     fn parse_battery_level(&self, raw_level_mv: u16) -> f32 {
@@ -76,127 +227,210 @@ impl BleNotifyParser {
         1.0 / (1.0 + (-K * (clamped_ratio - M)).exp())
     }
 
-    fn parse_notify_object(
-        &self,
-        iter: &mut std::slice::Iter<'_, u8>,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        match iter.next() {
-            Some(&obj_type) => {
-                tracing::debug!("Parsing object of type: {:02X?}", obj_type);
-                #[allow(clippy::manual_range_patterns)]
-                match obj_type {
-                    0x01 | 0x02 | 0x03 | 0x04 => {
-                        // Shake object
-                        // Skip the rest of the shake object data
-                        tracing::debug!("Skip parsing shake object data");
-                        for _ in 0..(16 - 1) {
-                            let _ = iter.next();
-                        }
-                    }
-                    0x10 | 0x11 | 0x12 => {
-                        // Electrical object
-                        // Skip the rest of the electrical object data
-                        tracing::debug!("Skip parsing electrical object data");
-                        for _ in 0..(16 - 1) {
-                            let _ = iter.next();
-                        }
-                    }
-                    0x81 => {
-                        // Device status object
-                        tracing::debug!("Parsing device status object");
-                        match self.parse_device_status_notify_object(iter) {
-                            Ok(device_status) => {
-                                tracing::info!(
-                                    "Device Status - Main {}: {:.0}% ({} mV), Left {}: {:.0}% ({} mV), Right {}: {:.0}% ({} mV)",
-                                    device_status.main_model,
-                                    self.parse_battery_level(device_status.main_battery_mv) * 100.0,
-                                    device_status.main_battery_mv,
-                                    device_status.left_model,
-                                    self.parse_battery_level(device_status.left_battery_mv) * 100.0,
-                                    device_status.left_battery_mv,
-                                    device_status.right_model,
-                                    self.parse_battery_level(device_status.right_battery_mv)
-                                        * 100.0,
-                                    device_status.right_battery_mv
-                                );
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to parse device status object: {}", e);
-                                return Err(e);
-                            }
-                        }
-                    }
-                    _ => {
-                        tracing::warn!(
-                            "Unknown object type received from TrueGear: {:02X?}",
-                            obj_type
-                        );
-                        return Err("Unknown object type".into());
-                    }
-                }
+    fn parse_notify_object(&self, object: &[u8]) -> Result<NotifyObject, TrueGearError> {
+        let obj_type = object[0];
+        tracing::debug!("Parsing object of type: {:02X?}", obj_type);
+
+        match obj_type {
+            0x01..=0x04 | 0x10..=0x12 => {
+                // Shake or electrical object
+                Ok(NotifyObject::Track(TrackObject::read_ble_bytes_from(
+                    object,
+                )?))
             }
-            None => {
-                tracing::error!("Incomplete object data received from TrueGear");
-                return Err("Incomplete object data".into());
+            0x81 => {
+                // Device status object
+                tracing::debug!("Parsing device status object");
+                let device_status = self
+                    .parse_device_status_notify_object(&object[1..])
+                    .inspect_err(|e| {
+                        tracing::error!("Failed to parse device status object: {}", e);
+                    })?;
+                Ok(NotifyObject::DeviceStatus(device_status))
+            }
+            _ => {
+                tracing::warn!(
+                    "Unknown object type received from TrueGear: {:02X?}",
+                    obj_type
+                );
+                Ok(NotifyObject::Unknown(object.to_vec()))
             }
         }
-        Ok(())
     }
 
     fn parse_device_status_notify_object(
         &self,
-        iter: &mut std::slice::Iter<'_, u8>,
-    ) -> Result<DeviceStatus, Box<dyn Error + Send + Sync>> {
-        fn consume_bytes<'a>(
-            iter: &mut std::slice::Iter<'a, u8>,
-            bytes: &[u8],
-        ) -> Result<(), Box<dyn Error + Send + Sync>> {
-            for byte in bytes.iter() {
-                match iter.next() {
-                    Some(&b) if b == *byte => {}
-                    Some(_) => return Err("Unknown device status object".into()),
-                    None => return Err("Incomplete device status object".into()),
-                }
-            }
-            Ok(())
-        }
+        data: &[u8],
+    ) -> Result<DeviceStatus, TrueGearError> {
+        let Some(fields) = data.strip_prefix(&[0x02, 0x03, 0x04]) else {
+            return Err(TrueGearError::Protocol(
+                "Unknown device status object".to_string(),
+            ));
+        };
 
-        fn read_u16<'a>(
-            iter: &mut std::slice::Iter<'a, u8>,
-        ) -> Result<u16, Box<dyn Error + Send + Sync>> {
-            let mut bytes_needed = 2;
-
-            let mut value: u16 = 0;
-            while bytes_needed > 0 {
-                match iter.next() {
-                    Some(&b) => {
-                        value = (value << 8) | (b as u16);
-                        bytes_needed -= 1;
-                    }
-                    None => return Err("Incomplete device status object".into()),
-                }
+        let read_u16 = |at: usize| -> Result<u16, TrueGearError> {
+            match fields.get(at..at + 2) {
+                Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+                None => Err(TrueGearError::Protocol(
+                    "Incomplete device status object".to_string(),
+                )),
             }
-            Ok(value)
+        };
+
+        Ok(DeviceStatus {
+            main_model: read_u16(0)?,
+            main_battery_mv: read_u16(2)?,
+            left_model: read_u16(4)?,
+            left_battery_mv: read_u16(6)?,
+            right_model: read_u16(8)?,
+            right_battery_mv: read_u16(10)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A status frame reporting the given main, left and right battery levels.
+    fn status_frame(main_model: u16, battery_mv: [u16; 3]) -> Vec<u8> {
+        let mut frame = vec![0x68, 0x68, 0x01, 0x81, 0x02, 0x03, 0x04];
+        for (model, mv) in [
+            (main_model, battery_mv[0]),
+            (261, battery_mv[1]),
+            (261, battery_mv[2]),
+        ] {
+            frame.extend(model.to_be_bytes());
+            frame.extend(mv.to_be_bytes());
         }
+        frame.push(0x16);
+        frame
+    }
 
-        consume_bytes(iter, &[0x02, 0x03, 0x04])?;
+    fn buffered(parser: &BleNotifyParser) -> Vec<u8> {
+        parser.buffer.lock().unwrap().clone()
+    }
 
-        let main_model = read_u16(iter)?;
-        let main_battery_mv = read_u16(iter)?;
+    #[test]
+    fn reassembles_frame_split_across_notifications() {
+        let parser = BleNotifyParser::new();
+        let mut receiver = parser.subscribe_device_status();
+        let frame = status_frame(519, [4000, 3900, 0]);
 
-        let left_model = read_u16(iter)?;
-        let left_battery_mv = read_u16(iter)?;
+        assert!(parser.on_message_received(&frame[..1]).is_empty());
+        assert!(parser.on_message_received(&frame[1..9]).is_empty());
+        let frames = parser.on_message_received(&frame[9..]);
 
-        let right_model = read_u16(iter)?;
-        let right_battery_mv = read_u16(iter)?;
+        assert_eq!(
+            frames,
+            [NotifyFrame {
+                objects: vec![NotifyObject::DeviceStatus(DeviceStatus {
+                    main_model: 519,
+                    main_battery_mv: 4000,
+                    left_model: 261,
+                    left_battery_mv: 3900,
+                    right_model: 261,
+                    right_battery_mv: 0,
+                })],
+            }]
+        );
+        assert!(buffered(&parser).is_empty());
 
-        Ok(DeviceStatus {
-            main_model,
-            main_battery_mv,
-            left_model,
-            left_battery_mv,
-            right_model,
-            right_battery_mv,
-        })
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.main.battery_mv, 4000);
+        assert_eq!(event.right.state, ModuleState::DetachedOrCharging);
+        assert_eq!(parser.device_status(), Some(event));
+    }
+
+    #[test]
+    fn yields_every_frame_of_a_notification() {
+        let parser = BleNotifyParser::new();
+        let mut data = status_frame(519, [4000, 3900, 3800]);
+        data.extend(status_frame(519, [3950, 3900, 3800]));
+
+        assert_eq!(parser.on_message_received(&data).len(), 2);
+        assert_eq!(parser.device_status().unwrap().main.battery_mv, 3950);
+    }
+
+    #[test]
+    fn resynchronizes_after_garbage() {
+        let parser = BleNotifyParser::new();
+        let mut data = vec![0x01, 0x02, 0x68, 0x03, 0x16];
+        data.extend(status_frame(519, [4000, 3900, 3800]));
+
+        assert_eq!(parser.on_message_received(&data).len(), 1);
+        assert!(buffered(&parser).is_empty());
+    }
+
+    #[test]
+    fn skips_false_header_before_frame() {
+        let parser = BleNotifyParser::new();
+        // a header whose object type is unknown, then a real frame
+        let mut data = vec![0x68, 0x68, 0x05, 0xEE, 0x00];
+        data.extend(status_frame(519, [4000, 3900, 3800]));
+
+        let frames = parser.on_message_received(&data);
+        assert_eq!(frames.len(), 1);
+        assert!(matches!(
+            frames[0].objects[0],
+            NotifyObject::DeviceStatus(_)
+        ));
+    }
+
+    #[test]
+    fn skips_header_of_malformed_frame() {
+        let parser = BleNotifyParser::new();
+        // a plausible frame that does not end in 0x16
+        let mut data = status_frame(519, [4000, 3900, 3800]);
+        *data.last_mut().unwrap() = 0x00;
+        data.extend(status_frame(519, [3950, 3900, 3800]));
+
+        let frames = parser.on_message_received(&data);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(parser.device_status().unwrap().main.battery_mv, 3950);
+    }
+
+    #[test]
+    fn keeps_header_bytes_inside_payload() {
+        let parser = BleNotifyParser::new();
+        // 0x6868 as a model number looks like a header in the middle of the frame
+        let frame = status_frame(0x6868, [0x6868, 3900, 3800]);
+
+        let frames = parser.on_message_received(&frame);
+        assert_eq!(frames.len(), 1);
+        let NotifyObject::DeviceStatus(status) = &frames[0].objects[0] else {
+            panic!("expected a status object, got {:?}", frames[0]);
+        };
+        assert_eq!(status.main_model, 0x6868);
+        assert_eq!(status.main_battery_mv, 0x6868);
+    }
+
+    #[test]
+    fn buffers_only_what_may_start_a_frame() {
+        let parser = BleNotifyParser::new();
+
+        // garbage is dropped, except a 0x68 that may start a header
+        parser.on_message_received(&[0x00; 64]);
+        assert!(buffered(&parser).is_empty());
+        parser.on_message_received(&[0x01, 0x02, 0x68]);
+        assert_eq!(buffered(&parser), [0x68]);
+
+        // a partial frame is held until complete, and no longer
+        let frame = status_frame(519, [4000, 3900, 3800]);
+        parser.on_message_received(&frame[1..10]);
+        assert_eq!(buffered(&parser), frame[..10]);
+        assert_eq!(parser.on_message_received(&frame[10..]).len(), 1);
+        assert!(buffered(&parser).is_empty());
+
+        // a header announcing the largest frame waits for at most that frame
+        let mut data = vec![0x68, 0x68, 0xFF];
+        for _ in 0..0xFF {
+            data.push(0x01);
+            data.extend([0x00; OBJECT_LEN - 1]);
+        }
+        parser.on_message_received(&data);
+        assert_eq!(buffered(&parser).len(), data.len());
+        parser.on_message_received(&[0x00, 0x00]);
+        assert!(buffered(&parser).len() < data.len());
     }
 }
@@ -0,0 +1,269 @@
+use crate::ble;
+use crate::ble_message_decoder::TRACK_OBJECT_LEN;
+use crate::device_hub::DeviceHub;
+use crate::effect_library::EffectLibrary;
+use crate::error::TrueGearError;
+use crate::transport::{ConnectionState, TrueGearTransport};
+use crate::true_gear_message::{Effect, Message};
+use std::error::Error;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+fn read_input(input: Option<&Path>) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut text = String::new();
+    match input {
+        Some(path) if path != Path::new("-") => {
+            text = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        }
+        _ => {
+            std::io::stdin().read_to_string(&mut text)?;
+        }
+    }
+    Ok(text)
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut digits = String::new();
+    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let token = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if !token.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Hex input contains non-hex characters: {}", token).into());
+        }
+        // a byte must not straddle two tokens
+        if !token.len().is_multiple_of(2) {
+            return Err(format!("Hex input has an odd number of digits: {}", token).into());
+        }
+        digits.push_str(token);
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("Invalid hex byte: {}", &digits[i..i + 2]).into())
+        })
+        .collect()
+}
+
+/// Prints the BLE bytes for an effect read from `input` (or stdin), one
+/// EffectObject per line.
+///
+/// The input may be a raw effect (see `doc/effect.schema.json`) or a command
+/// message with a base64 body (see `doc/command.schema.json`).
+pub fn encode(
+    input: Option<&Path>,
+    electical_effect_ratio: f32,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let text = read_input(input)?;
+
+    let effect = match serde_json::from_str::<Message>(&text) {
+        Ok(message) => message.body,
+        Err(_) => serde_json::from_str::<Effect>(&text)
+            .map_err(|e| format!("Input is neither a message nor an effect: {}", e))?,
+    };
+
+    let mut buffer = Vec::new();
+    effect.write_ble_bytes_to(&mut buffer, electical_effect_ratio)?;
+
+    let mut rest = buffer.as_slice();
+    while rest.len() >= 3 {
+        let (effect_object, tail) = rest.split_at(3 + rest[2] as usize * TRACK_OBJECT_LEN + 1);
+        let line: String = effect_object.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{}", line);
+        rest = tail;
+    }
+
+    Ok(())
+}
+
+/// Decodes hex EffectObjects from `hex` (or stdin if empty) and prints them as
+/// effect JSON, or as command messages if `as_message` is set.
+pub fn decode(
+    hex: &[String],
+    electical_effect_ratio: f32,
+    as_message: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let text = if hex.is_empty() {
+        read_input(None)?
+    } else {
+        hex.join(" ")
+    };
+
+    let effects = Effect::read_ble_bytes_from(&parse_hex(&text)?, electical_effect_ratio)?;
+
+    for effect in effects {
+        let output = if as_message {
+            serde_json::to_string(&Message {
+                method: "play_no_registered".into(),
+                body: effect,
+            })?
+        } else {
+            serde_json::to_string_pretty(&effect)?
+        };
+        println!("{}", output);
+    }
+
+    Ok(())
+}
+
+/// Scans for TrueGear devices for `duration` and prints them as a table, or
+/// as JSON if `json` is set.
+pub async fn scan(
+    adapter: Option<&str>,
+    duration: Duration,
+    json: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tracing::info!("Scanning for {:?}...", duration);
+    let devices = ble::scan(adapter, duration).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+
+    if devices.is_empty() {
+        println!("No TrueGear devices found");
+        return Ok(());
+    }
+
+    let yes_no = |found: bool, probed: bool| match (found, probed) {
+        (_, false) => "?",
+        (true, true) => "yes",
+        (false, true) => "no",
+    };
+
+    let mut rows = vec![[
+        "ID".to_string(),
+        "ADDRESS".to_string(),
+        "NAME".to_string(),
+        "RSSI".to_string(),
+        "WRITE".to_string(),
+        "NOTIFY".to_string(),
+        "NOTE".to_string(),
+    ]];
+    for device in &devices {
+        let probed = device.probe_error.is_none();
+        rows.push([
+            device.id.clone(),
+            device.address.clone(),
+            device.name.clone().unwrap_or_default(),
+            device.rssi.map(|rssi| rssi.to_string()).unwrap_or_default(),
+            yes_no(device.write_characteristic, probed).to_string(),
+            yes_no(device.notify_characteristic, probed).to_string(),
+            device.probe_error.clone().unwrap_or_default(),
+        ]);
+    }
+
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+
+    Ok(())
+}
+
+/// Plays the library effect `name` on every device in `device_hub`, scaled by
+/// `intensity` and `duration` and repeated `repeat` times if given, and
+/// returns once it has finished, or on Ctrl-C if it loops.
+pub async fn play<T: TrueGearTransport>(
+    device_hub: &DeviceHub<T>,
+    library: &EffectLibrary,
+    name: &str,
+    intensity: f32,
+    duration: f32,
+    repeat: Option<u32>,
+    connect_timeout: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut effect = library
+        .get(name)
+        .ok_or_else(|| TrueGearError::UnknownEffect(name.to_string()))
+        .and_then(|effect| effect.scaled(intensity, duration))
+        .map_err(|e| e.to_string())?;
+    if let Some(repeat) = repeat {
+        effect.repeat = repeat;
+    }
+    effect.validate().map_err(|e| e.to_string())?;
+
+    device_hub.start().await?;
+
+    for device in device_hub.devices() {
+        let controller = &device.controller;
+        let mut connection_state_receiver = controller.subscribe_connection_state();
+        let connected = tokio::time::timeout(connect_timeout, async {
+            while controller.connection_state().await != ConnectionState::Connected {
+                let _ = connection_state_receiver.recv().await;
+            }
+        })
+        .await;
+        if connected.is_err() {
+            device_hub.close().await?;
+            return Err(format!("Timed out connecting to device {}", device.alias).into());
+        }
+    }
+
+    for device in device_hub.devices() {
+        let message = Message {
+            method: "play".to_string(),
+            body: effect.clone(),
+        };
+        device.controller.clone().send_ble_message(message).await?;
+        tracing::info!("Playing {} on {}", effect.name, device.alias);
+    }
+
+    if effect.repeat == 0 {
+        tokio::signal::ctrl_c().await?;
+    } else {
+        let length = Duration::from_millis(effect.length() as u64);
+        tokio::time::sleep(length * effect.repeat).await;
+    }
+
+    // a keep or looping effect would otherwise outlive the command
+    if effect.keep || effect.repeat == 0 {
+        for device in device_hub.devices() {
+            device.controller.clone().stop(Some(&effect.name)).await?;
+        }
+    }
+
+    device_hub.close().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_hex;
+
+    #[test]
+    fn parse_hex_accepts_prefixes_and_separators() {
+        assert_eq!(
+            parse_hex("0x68 0X68,01\n16").unwrap(),
+            vec![0x68, 0x68, 0x01, 0x16]
+        );
+    }
+
+    #[test]
+    fn parse_hex_rejects_signs_and_repeated_prefixes() {
+        assert!(parse_hex("+1 00").is_err());
+        assert!(parse_hex("0x0x12").is_err());
+        assert!(parse_hex("12 3").is_err());
+        assert!(parse_hex("6 86 8").is_err());
+        assert!(parse_hex("0x6 8").is_err());
+        assert_eq!(parse_hex("6868 16").unwrap(), vec![0x68, 0x68, 0x16]);
+    }
+}
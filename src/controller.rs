@@ -1,23 +1,263 @@
-use crate::{ble, ble_notify_parser, predefined, true_gear_message};
-use std::error::Error;
+use crate::ble_message_ext::check_fits_writes;
+use crate::error::TrueGearError;
+use crate::transport::{ConnectionState, TrueGearTransport};
+use crate::true_gear_message::{ActionType, Effect, IntensityMode, Track};
+use crate::{ble_notify_parser, predefined, true_gear_message};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, broadcast, oneshot};
+
+#[derive(Debug, Clone)]
+pub struct ControllerOptions {
+    pub electical_effect_ratio: f32,
+    /// Maximum number of effects held while the device is not connected.
+    pub pending_effect_capacity: usize,
+    /// Queued effects older than this are dropped instead of played.
+    pub pending_effect_ttl: Duration,
+    /// What happens to an effect arriving while a higher-priority effect
+    /// plays on the same actuators.
+    pub priority_policy: PriorityPolicy,
+    /// Longest time a deferred effect waits before it is dropped.
+    pub priority_defer_max: Duration,
+    /// Effects sent within this long of each other are merged into as few
+    /// writes as fit the device. Zero writes every effect on its own.
+    pub coalesce_window: Duration,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityPolicy {
+    /// Reject the lower-priority effect
+    Drop,
+    /// Play the lower-priority effect once the actuators are free
+    Defer,
+}
+
+// Shortest stop frame, so the device has time to apply it
+const STOP_FRAME_MIN: Duration = Duration::from_millis(100);
+// How often a deferred effect blocked by a keep effect checks again
+const DEFER_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The actuators and play time an effect occupies, remembered so that it
+/// can be stopped.
+#[derive(Debug, Clone)]
+struct EffectFootprint {
+    name: String,
+    uuid: String,
+    shake: BTreeSet<u8>,
+    electrical: BTreeSet<u8>,
+    duration: Duration,
+    /// Keep effects hold their last shake intensity after `duration`.
+    held: bool,
+    priority: u16,
+    /// The play it was written in, once it is; shared by the windows of a
+    /// scheduled effect.
+    play_id: Option<u64>,
+}
+
+impl EffectFootprint {
+    fn of(effect: &Effect) -> Self {
+        let mut shake = BTreeSet::new();
+        let mut electrical = BTreeSet::new();
+        for track in &effect.tracks {
+            match track.action_type {
+                ActionType::Shake => shake.extend(&track.index),
+                ActionType::Electrical => electrical.extend(&track.index),
+            }
+        }
+
+        EffectFootprint {
+            name: effect.name.clone(),
+            uuid: effect.uuid.clone(),
+            held: effect.keep && !shake.is_empty(),
+            shake,
+            electrical,
+            duration: Duration::from_millis(effect.length() as u64),
+            priority: effect.priority,
+            play_id: None,
+        }
+    }
+
+    fn overlaps(&self, other: &EffectFootprint) -> bool {
+        !self.shake.is_disjoint(&other.shake) || !self.electrical.is_disjoint(&other.electrical)
+    }
+
+    /// Whether `name` names this effect, by name or by uuid.
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.uuid == name
+    }
+}
+
+struct ActiveEffect {
+    footprint: EffectFootprint,
+    started_at: Instant,
+}
+
+impl ActiveEffect {
+    fn remaining(&self) -> Duration {
+        self.footprint
+            .duration
+            .saturating_sub(self.started_at.elapsed())
+    }
+
+    fn is_finished(&self) -> bool {
+        !self.footprint.held && self.remaining().is_zero()
+    }
+}
+
+/// How an incoming effect fits with what is already playing.
+enum Arbitration {
+    /// Play now, silencing these lower-priority plays first, by play id.
+    Play { preempted: BTreeMap<u64, String> },
+    /// A higher-priority effect holds some of the actuators, for at most
+    /// this long (`None` if it is a keep effect).
+    Blocked {
+        blocker: String,
+        remaining: Option<Duration>,
+    },
+}
+
+/// A lower-priority effect waiting for its actuators to be free.
+struct DeferredEffect {
+    id: u64,
+    effect: Effect,
+}
+
+/// An effect too long for one frame, or repeating, being played window by
+/// window, or an effect waiting for the time it was asked to play at.
+struct ScheduledEffect {
+    id: u64,
+    footprint: EffectFootprint,
+}
+
+/// An encoded effect waiting for the coalescing window to close, and where
+/// to report the result of the write it ends up in.
+struct Submission {
+    buffer: Vec<u8>,
+    footprints: Vec<EffectFootprint>,
+    result: oneshot::Sender<Result<(), TrueGearError>>,
+}
+
+/// An effect handed to the controller, with the size of its frame (`None` if
+/// it was deferred or scheduled to start later) and, with a coalescing window,
+/// the write it is waiting to go out in.
+pub struct Submitted {
+    frame_size: Option<usize>,
+    written: Option<oneshot::Receiver<Result<(), TrueGearError>>>,
+}
+
+impl Submitted {
+    fn done(frame_size: Option<usize>) -> Self {
+        Submitted {
+            frame_size,
+            written: None,
+        }
+    }
+
+    /// Whether the effect is still waiting for the coalescing window to close.
+    pub fn is_waiting(&self) -> bool {
+        self.written.is_some()
+    }
+
+    /// Waits for the write the effect goes out in, returning the size of its
+    /// frame, or `None` if it was deferred or scheduled.
+    pub async fn written(self) -> Result<Option<usize>, TrueGearError> {
+        if let Some(written) = self.written {
+            // the flush only goes away without answering if it panicked
+            written.await.unwrap_or(Err(TrueGearError::NotConnected))?;
+        }
+        Ok(self.frame_size)
+    }
+}
+
+struct PendingEffect {
+    buffer: Vec<u8>,
+    footprints: Vec<EffectFootprint>,
+    queued_at: Instant,
+}
+
+/// An effect that overrides `shake` and `electrical` with zero intensity for
+/// `duration`.
+fn stop_effect(
+    name: &str,
+    shake: BTreeSet<u8>,
+    electrical: BTreeSet<u8>,
+    duration: Duration,
+) -> Effect {
+    let end_time = duration.as_millis().min(u16::MAX as u128) as u32;
+    let track = |action_type: ActionType, index: BTreeSet<u8>| Track {
+        start_time: 0,
+        end_time,
+        stop_name: "".into(),
+        start_intensity: 0,
+        end_intensity: 0,
+        intensity_mode: IntensityMode::Const,
+        action_type,
+        once: false,
+        interval: 0,
+        index: index.into_iter().collect(),
+    };
+
+    let mut tracks = Vec::new();
+    if !shake.is_empty() {
+        tracks.push(track(ActionType::Shake, shake));
+    }
+    if !electrical.is_empty() {
+        tracks.push(track(ActionType::Electrical, electrical));
+    }
+
+    Effect {
+        name: format!("Stop {}", name),
+        uuid: format!("Stop {}", name),
+        // held at zero, so a stopped keep effect does not resume afterwards
+        keep: true,
+        priority: 0,
+        tracks,
+        repeat: 1,
+    }
+}
 
 #[derive(Clone)]
-pub struct TrueGearBLEController {
-    true_gear_connection: ble::TrueGearBLEConnection,
+pub struct TrueGearBLEController<T: TrueGearTransport> {
+    true_gear_connection: T,
     electical_effect_ratio: f32,
-    #[allow(unused)]
     ble_notify_parser: ble_notify_parser::BleNotifyParser,
+    pending_effects: Arc<Mutex<VecDeque<PendingEffect>>>,
+    pending_effect_capacity: usize,
+    pending_effect_ttl: Duration,
+    active_effects: Arc<Mutex<Vec<ActiveEffect>>>,
+    priority_policy: PriorityPolicy,
+    priority_defer_max: Duration,
+    deferred_effects: Arc<Mutex<Vec<DeferredEffect>>>,
+    next_deferred_id: Arc<AtomicU64>,
+    scheduled_effects: Arc<Mutex<Vec<ScheduledEffect>>>,
+    /// Ids of plays, also identifying scheduled effects.
+    next_play_id: Arc<AtomicU64>,
+    coalesce_window: Duration,
+    submissions: Arc<Mutex<Vec<Submission>>>,
 }
 
-impl TrueGearBLEController {
-    pub async fn build(electical_effect_ratio: f32) -> Self {
-        let true_gear_connection = ble::TrueGearBLEConnection::new();
+impl<T: TrueGearTransport> TrueGearBLEController<T> {
+    pub async fn build(true_gear_connection: T, options: ControllerOptions) -> Self {
         let mut true_gear_connection_clone = true_gear_connection.clone();
         let ble_notify_parser = ble_notify_parser::BleNotifyParser::new();
         let instance = TrueGearBLEController {
             true_gear_connection,
-            electical_effect_ratio,
+            electical_effect_ratio: options.electical_effect_ratio,
             ble_notify_parser: ble_notify_parser.clone(),
+            pending_effects: Arc::new(Mutex::new(VecDeque::new())),
+            pending_effect_capacity: options.pending_effect_capacity,
+            pending_effect_ttl: options.pending_effect_ttl,
+            active_effects: Arc::new(Mutex::new(Vec::new())),
+            priority_policy: options.priority_policy,
+            priority_defer_max: options.priority_defer_max,
+            deferred_effects: Arc::new(Mutex::new(Vec::new())),
+            next_deferred_id: Arc::new(AtomicU64::new(0)),
+            scheduled_effects: Arc::new(Mutex::new(Vec::new())),
+            next_play_id: Arc::new(AtomicU64::new(0)),
+            coalesce_window: options.coalesce_window,
+            submissions: Arc::new(Mutex::new(Vec::new())),
         };
         let controller_clone = instance.clone();
 
@@ -32,7 +272,9 @@ impl TrueGearBLEController {
 
         true_gear_connection_clone
             .set_on_message_received(move |data: &[u8]| {
-                let _ = ble_notify_parser.on_message_received(data);
+                for frame in ble_notify_parser.on_message_received(data) {
+                    tracing::debug!("Received frame from TrueGear: {:?}", frame);
+                }
             })
             .await;
 
@@ -43,6 +285,659 @@ impl TrueGearBLEController {
         let _ = self
             .send_ble_messages(predefined::on_connected_message())
             .await;
+
+        self.flush_pending_effects().await;
+    }
+
+    /// Holds an effect that could not be sent until the device is connected,
+    /// dropping the oldest one if the queue is full.
+    async fn queue_pending_effect(&self, buffer: Vec<u8>, footprints: Vec<EffectFootprint>) {
+        if self.pending_effect_capacity == 0 {
+            return;
+        }
+
+        let mut pending_effects = self.pending_effects.lock().await;
+        if pending_effects.len() >= self.pending_effect_capacity {
+            pending_effects.pop_front();
+            tracing::warn!("Pending effect queue is full, dropping the oldest effect");
+        }
+        pending_effects.push_back(PendingEffect {
+            buffer,
+            footprints,
+            queued_at: Instant::now(),
+        });
+        tracing::info!(
+            "Device not connected, queued effect ({} pending)",
+            pending_effects.len()
+        );
+    }
+
+    /// Sends every queued effect that has not expired in a single write.
+    async fn flush_pending_effects(&mut self) {
+        let pending_effects = std::mem::take(&mut *self.pending_effects.lock().await);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut footprints = Vec::new();
+        let mut expired = 0;
+        for pending_effect in pending_effects {
+            if pending_effect.queued_at.elapsed() > self.pending_effect_ttl {
+                expired += 1;
+            } else {
+                buffer.extend(pending_effect.buffer);
+                footprints.extend(pending_effect.footprints);
+            }
+        }
+
+        if expired > 0 {
+            tracing::info!("Dropped {} expired pending effects", expired);
+        }
+
+        if buffer.is_empty() {
+            return;
+        }
+
+        tracing::debug!(
+            "Sending pending message bytes ({}): {:02X?}",
+            buffer.len(),
+            buffer
+        );
+
+        match self.true_gear_connection.send_data(&buffer).await {
+            Ok(()) => self.mark_active(footprints).await,
+            Err(e) => tracing::error!("Failed to send pending effects: {}", e),
+        }
+    }
+
+    /// Remembers effects that were just written to the device.
+    async fn mark_active(&self, footprints: Vec<EffectFootprint>) {
+        let started_at = Instant::now();
+        let mut active_effects = self.active_effects.lock().await;
+        active_effects.retain(|active_effect| !active_effect.is_finished());
+        active_effects.extend(footprints.into_iter().map(|footprint| ActiveEffect {
+            footprint,
+            started_at,
+        }));
+    }
+
+    /// Sends `buffer`, or queues it if the device is not connected yet.
+    ///
+    /// With a coalescing window, `buffer` is written when the window closes,
+    /// together with everything else sent in the meantime, and the result of
+    /// that write is returned through the receiver.
+    async fn send_or_queue(
+        &mut self,
+        buffer: Vec<u8>,
+        footprints: Vec<EffectFootprint>,
+    ) -> Result<Option<oneshot::Receiver<Result<(), TrueGearError>>>, TrueGearError> {
+        if self.coalesce_window.is_zero() {
+            self.write_or_queue(vec![(buffer, footprints)]).await?;
+            return Ok(None);
+        }
+
+        let (result, written) = oneshot::channel();
+        let mut submissions = self.submissions.lock().await;
+        if submissions.is_empty() {
+            let mut controller = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(controller.coalesce_window).await;
+                controller.flush_submissions().await;
+            });
+        }
+        submissions.push(Submission {
+            buffer,
+            footprints,
+            result,
+        });
+        Ok(Some(written))
+    }
+
+    /// Writes the effects sent during the coalescing window, packing them
+    /// into as few writes as fit the device.
+    async fn flush_submissions(&mut self) {
+        let submissions = std::mem::take(&mut *self.submissions.lock().await);
+        let max_write_size = self.true_gear_connection.max_write_size();
+
+        let mut batches: Vec<Vec<Submission>> = Vec::new();
+        let mut batch_size = 0;
+        for submission in submissions {
+            match batches.last_mut() {
+                Some(batch) if batch_size + submission.buffer.len() <= max_write_size => {
+                    batch_size += submission.buffer.len();
+                    batch.push(submission);
+                }
+                _ => {
+                    batch_size = submission.buffer.len();
+                    batches.push(vec![submission]);
+                }
+            }
+        }
+
+        for batch in batches {
+            tracing::debug!("Coalesced {} effects into one write", batch.len());
+            let (effects, results): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .map(|submission| {
+                    (
+                        (submission.buffer, submission.footprints),
+                        submission.result,
+                    )
+                })
+                .unzip();
+
+            let written = self.write_or_queue(effects).await;
+            if let Err(e) = &written {
+                tracing::error!("Failed to send coalesced effects: {}", e);
+            }
+            for result in results {
+                // the sender may have stopped waiting
+                let _ = result.send(written.clone());
+            }
+        }
+    }
+
+    /// Writes the encoded `effects` in one go, or queues each of them if the
+    /// device is not connected yet.
+    async fn write_or_queue(
+        &mut self,
+        effects: Vec<(Vec<u8>, Vec<EffectFootprint>)>,
+    ) -> Result<(), TrueGearError> {
+        let buffer: Vec<u8> = effects
+            .iter()
+            .flat_map(|(buffer, _)| buffer)
+            .copied()
+            .collect();
+
+        match self.true_gear_connection.send_data(&buffer).await {
+            Ok(()) => {
+                let footprints = effects
+                    .into_iter()
+                    .flat_map(|(_, footprints)| footprints)
+                    .collect();
+                self.mark_active(footprints).await;
+                return Ok(());
+            }
+            Err(e) if self.pending_effect_capacity == 0 => return Err(e),
+            Err(TrueGearError::NotConnected | TrueGearError::Searching) => {}
+            // the link may have dropped before the transport noticed
+            Err(e) => {
+                if self.true_gear_connection.connection_state().await == ConnectionState::Connected
+                {
+                    return Err(e);
+                }
+            }
+        }
+
+        for (buffer, footprints) in effects {
+            self.queue_pending_effect(buffer, footprints).await;
+        }
+        Ok(())
+    }
+
+    /// Cancels the queued and scheduled effects named `name` (or every one if
+    /// `None`) and returns a frame that silences the matching playing effects.
+    /// The play `sparing` is left alone, so that an effect can stop earlier
+    /// plays of itself.
+    async fn take_stop_frame(
+        &self,
+        name: Option<&str>,
+        sparing: Option<u64>,
+    ) -> Result<Option<Vec<u8>>, TrueGearError> {
+        let is_spared = |play_id: u64| sparing == Some(play_id);
+        let matches = |footprint: &EffectFootprint| {
+            name.is_none_or(|name| footprint.matches(name))
+                && !footprint.play_id.is_some_and(is_spared)
+        };
+        self.cancel_effects(matches, is_spared).await;
+        self.take_silence_frame(name.unwrap_or("all"), name.is_none(), matches)
+            .await
+    }
+
+    /// Cancels the queued, deferred, scheduled and coalescing effects that
+    /// `matches` picks, keeping the scheduled plays `is_spared` picks.
+    async fn cancel_effects(
+        &self,
+        matches: impl Fn(&EffectFootprint) -> bool,
+        is_spared: impl Fn(u64) -> bool,
+    ) {
+        let matches = &matches;
+
+        let mut pending_effects = self.pending_effects.lock().await;
+        let queued = pending_effects.len();
+        pending_effects.retain(|pending_effect| !pending_effect.footprints.iter().any(matches));
+        let mut cancelled = queued - pending_effects.len();
+        drop(pending_effects);
+
+        let mut deferred_effects = self.deferred_effects.lock().await;
+        let deferred = deferred_effects.len();
+        deferred_effects
+            .retain(|deferred_effect| !matches(&EffectFootprint::of(&deferred_effect.effect)));
+        cancelled += deferred - deferred_effects.len();
+        drop(deferred_effects);
+
+        let mut scheduled_effects = self.scheduled_effects.lock().await;
+        let scheduled = scheduled_effects.len();
+        scheduled_effects.retain(|scheduled_effect| {
+            is_spared(scheduled_effect.id) || !matches(&scheduled_effect.footprint)
+        });
+        cancelled += scheduled - scheduled_effects.len();
+        drop(scheduled_effects);
+
+        let mut submissions = self.submissions.lock().await;
+        let (stopped, submitted): (Vec<_>, Vec<_>) = std::mem::take(&mut *submissions)
+            .into_iter()
+            .partition(|submission| submission.footprints.iter().any(matches));
+        *submissions = submitted;
+        drop(submissions);
+        cancelled += stopped.len();
+        for submission in stopped {
+            // it was accepted, then stopped before it was written
+            let _ = submission.result.send(Ok(()));
+        }
+        if cancelled > 0 {
+            tracing::info!("Cancelled {} pending effects", cancelled);
+        }
+    }
+
+    /// Returns a frame named after `name` that silences what the playing
+    /// effects `matches` picks occupy, or every actuator if `everything`, and
+    /// forgets them.
+    async fn take_silence_frame(
+        &self,
+        name: &str,
+        everything: bool,
+        matches: impl Fn(&EffectFootprint) -> bool,
+    ) -> Result<Option<Vec<u8>>, TrueGearError> {
+        let mut shake = BTreeSet::new();
+        let mut electrical = BTreeSet::new();
+        let mut duration = STOP_FRAME_MIN;
+
+        let mut active_effects = self.active_effects.lock().await;
+        active_effects.retain(|active_effect| {
+            if active_effect.is_finished() {
+                return false;
+            }
+            if !matches(&active_effect.footprint) {
+                return true;
+            }
+            shake.extend(&active_effect.footprint.shake);
+            electrical.extend(&active_effect.footprint.electrical);
+            duration = duration.max(active_effect.remaining());
+            false
+        });
+        drop(active_effects);
+
+        if everything {
+            shake.extend(predefined::shake_flag_shift_map().keys());
+            electrical.extend(predefined::electrical_flag_shift_map().keys());
+        }
+
+        if shake.is_empty() && electrical.is_empty() {
+            return Ok(None);
+        }
+
+        let effect = stop_effect(name, shake, electrical, duration);
+        let mut buffer = Vec::new();
+        effect.write_ble_bytes_to(&mut buffer, self.electical_effect_ratio)?;
+        Ok(Some(buffer))
+    }
+
+    /// Stops the effect named `name` (by name or uuid), or every effect if
+    /// `None`, returning the size of the stop frame if one was written.
+    pub async fn stop(&mut self, name: Option<&str>) -> Result<Option<usize>, TrueGearError> {
+        let Some(buffer) = self.take_stop_frame(name, None).await? else {
+            return Ok(None);
+        };
+
+        tracing::debug!("Sending stop bytes ({}): {:02X?}", buffer.len(), buffer);
+
+        match self.true_gear_connection.send_data(&buffer).await {
+            Ok(()) => Ok(Some(buffer.len())),
+            // nothing is playing on a device that is not connected
+            Err(TrueGearError::NotConnected | TrueGearError::Searching) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Weighs `effect` against the effects playing on the same actuators.
+    async fn arbitrate(&self, effect: &Effect) -> Arbitration {
+        let footprint = EffectFootprint::of(effect);
+        let mut preempted = BTreeMap::new();
+        let mut blocker: Option<&ActiveEffect> = None;
+
+        let mut active_effects = self.active_effects.lock().await;
+        active_effects.retain(|active_effect| !active_effect.is_finished());
+        for active_effect in active_effects.iter() {
+            if !active_effect.footprint.overlaps(&footprint) {
+                continue;
+            }
+            let priority = active_effect.footprint.priority;
+            if priority < footprint.priority
+                && let Some(play_id) = active_effect.footprint.play_id
+            {
+                preempted.insert(play_id, active_effect.footprint.name.clone());
+            } else if priority > footprint.priority
+                && blocker.is_none_or(|blocker| priority > blocker.footprint.priority)
+            {
+                blocker = Some(active_effect);
+            }
+        }
+
+        match blocker {
+            Some(blocker) => Arbitration::Blocked {
+                blocker: blocker.footprint.name.clone(),
+                remaining: (!blocker.footprint.held).then(|| blocker.remaining()),
+            },
+            None => Arbitration::Play { preempted },
+        }
+    }
+
+    /// Plays `effect` unless a higher-priority effect holds its actuators, in
+    /// which case it is dropped or deferred until `defer_deadline`.
+    async fn submit(
+        &mut self,
+        effect: Effect,
+        defer_deadline: Instant,
+    ) -> Result<Submitted, TrueGearError> {
+        let (blocker, remaining) = match self.arbitrate(&effect).await {
+            Arbitration::Play { preempted } => {
+                for name in preempted.values() {
+                    tracing::debug!("{} preempts {}", effect.name, name);
+                }
+                return self.start_effect(effect, preempted).await;
+            }
+            Arbitration::Blocked { blocker, remaining } => (blocker, remaining),
+        };
+
+        if self.priority_policy == PriorityPolicy::Drop || Instant::now() >= defer_deadline {
+            return Err(TrueGearError::LowerPriority {
+                effect: effect.name,
+                blocker,
+            });
+        }
+
+        // validate now so the sender hears about a bad effect
+        effect.validate()?;
+
+        let id = self.next_deferred_id.fetch_add(1, Ordering::Relaxed);
+        let wait = Self::defer_wait(remaining, defer_deadline);
+        tracing::debug!(
+            "Deferring {} for {:?} behind {}",
+            effect.name,
+            wait,
+            blocker
+        );
+        self.deferred_effects
+            .lock()
+            .await
+            .push(DeferredEffect { id, effect });
+
+        let mut controller = self.clone();
+        tokio::spawn(async move {
+            controller
+                .retry_deferred_effect(id, wait, defer_deadline)
+                .await;
+        });
+
+        Ok(Submitted::done(None))
+    }
+
+    fn defer_wait(remaining: Option<Duration>, defer_deadline: Instant) -> Duration {
+        remaining
+            .unwrap_or(DEFER_RECHECK_INTERVAL)
+            .min(defer_deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Plays the deferred effect `id` once nothing of a higher priority holds
+    /// its actuators, or drops it at `defer_deadline`. Returns early if the
+    /// effect was stopped while waiting.
+    async fn retry_deferred_effect(
+        &mut self,
+        id: u64,
+        mut wait: Duration,
+        defer_deadline: Instant,
+    ) {
+        loop {
+            tokio::time::sleep(wait).await;
+
+            let effect = {
+                let mut deferred_effects = self.deferred_effects.lock().await;
+                let Some(position) = deferred_effects.iter().position(|e| e.id == id) else {
+                    return;
+                };
+                deferred_effects.remove(position).effect
+            };
+
+            match self.arbitrate(&effect).await {
+                Arbitration::Play { preempted } => {
+                    let result = match self.start_effect(effect.clone(), preempted).await {
+                        Ok(submitted) => submitted.written().await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        tracing::warn!("Failed to play deferred effect {}: {}", effect.name, e);
+                    }
+                    return;
+                }
+                Arbitration::Blocked { blocker, remaining } => {
+                    if Instant::now() >= defer_deadline {
+                        tracing::info!(
+                            "{}",
+                            TrueGearError::LowerPriority {
+                                effect: effect.name,
+                                blocker,
+                            }
+                        );
+                        return;
+                    }
+                    wait = Self::defer_wait(remaining, defer_deadline);
+                    self.deferred_effects
+                        .lock()
+                        .await
+                        .push(DeferredEffect { id, effect });
+                }
+            }
+        }
+    }
+
+    /// Plays `effect` after silencing `preempted`, scheduling it if it does not
+    /// fit in one frame.
+    async fn start_effect(
+        &mut self,
+        effect: Effect,
+        preempted: BTreeMap<u64, String>,
+    ) -> Result<Submitted, TrueGearError> {
+        if effect.needs_scheduling() {
+            self.schedule(effect, preempted).await.map(Submitted::done)
+        } else {
+            let play_id = self.new_play_id();
+            self.play(&[&effect], preempted, play_id).await
+        }
+    }
+
+    fn new_play_id(&self) -> u64 {
+        self.next_play_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Plays `effect` window by window until it ends or is stopped. The first
+    /// window is written straight away if the effect starts with one, and
+    /// its size returned; the rest are sent from a task.
+    async fn schedule(
+        &mut self,
+        effect: Effect,
+        preempted: BTreeMap<u64, String>,
+    ) -> Result<Option<usize>, TrueGearError> {
+        effect.validate()?;
+
+        let id = self.new_play_id();
+        tracing::debug!(
+            "Scheduling {} ({} ms, repeat {})",
+            effect.name,
+            effect.length(),
+            effect.repeat
+        );
+        self.scheduled_effects.lock().await.push(ScheduledEffect {
+            id,
+            footprint: EffectFootprint::of(&effect),
+        });
+
+        let started_at = tokio::time::Instant::now();
+        let mut windows = effect.windows().peekable();
+        let frame_size = match windows.next_if(|(offset, _)| offset.is_zero()) {
+            Some((_, window)) => match self.play_now(&window, preempted, id).await {
+                Ok(frame_size) => frame_size,
+                Err(e) => {
+                    self.unschedule(id).await;
+                    return Err(e);
+                }
+            },
+            None => None,
+        };
+
+        let mut controller = self.clone();
+        tokio::spawn(async move {
+            controller
+                .run_schedule(id, &effect.name, started_at, windows)
+                .await;
+        });
+
+        Ok(frame_size)
+    }
+
+    /// Sends each of `windows` when it is due, skipping those blocked by a
+    /// higher-priority effect. Returns early if the effect was stopped.
+    async fn run_schedule(
+        &mut self,
+        id: u64,
+        name: &str,
+        started_at: tokio::time::Instant,
+        windows: impl Iterator<Item = (Duration, Effect)>,
+    ) {
+        for (offset, window) in windows {
+            tokio::time::sleep_until(started_at + offset).await;
+
+            if !self.is_scheduled(id).await {
+                return;
+            }
+
+            // the previous window has ended, but a keep effect would stay
+            // active for good
+            self.active_effects
+                .lock()
+                .await
+                .retain(|active_effect| active_effect.footprint.play_id != Some(id));
+
+            let preempted = match self.arbitrate(&window).await {
+                Arbitration::Play { preempted } => preempted,
+                Arbitration::Blocked { blocker, .. } => {
+                    tracing::debug!("Skipping a window of {} behind {}", name, blocker);
+                    continue;
+                }
+            };
+            if let Err(e) = self.play_now(&window, preempted, id).await {
+                tracing::warn!("Failed to play a window of {}: {}", name, e);
+            }
+        }
+
+        self.unschedule(id).await;
+    }
+
+    /// Whether the scheduled effect `id` has not been stopped.
+    async fn is_scheduled(&self, id: u64) -> bool {
+        self.scheduled_effects
+            .lock()
+            .await
+            .iter()
+            .any(|scheduled_effect| scheduled_effect.id == id)
+    }
+
+    /// Forgets the scheduled effect `id`, returning whether it had not been
+    /// stopped.
+    async fn unschedule(&self, id: u64) -> bool {
+        let mut scheduled_effects = self.scheduled_effects.lock().await;
+        let scheduled = scheduled_effects.len();
+        scheduled_effects.retain(|scheduled_effect| scheduled_effect.id != id);
+        scheduled_effects.len() < scheduled
+    }
+
+    /// Plays `effect` as part of play `play_id` and waits for the write it
+    /// goes out in.
+    async fn play_now(
+        &mut self,
+        effect: &Effect,
+        preempted: BTreeMap<u64, String>,
+        play_id: u64,
+    ) -> Result<Option<usize>, TrueGearError> {
+        self.play(&[effect], preempted, play_id)
+            .await?
+            .written()
+            .await
+    }
+
+    /// Encodes `effects` into one write as play `play_id`, preceded by
+    /// frames silencing `preempted` and stopping the effects their tracks
+    /// name in `stop_name`, and sends or queues it.
+    async fn play(
+        &mut self,
+        effects: &[&Effect],
+        preempted: BTreeMap<u64, String>,
+        play_id: u64,
+    ) -> Result<Submitted, TrueGearError> {
+        let mut buffer: Vec<u8> = Vec::new();
+        for effect in effects {
+            let mut buffer_effect: Vec<u8> = Vec::new();
+            effect.write_ble_bytes_to(&mut buffer_effect, self.electical_effect_ratio)?;
+            check_fits_writes(&buffer_effect, self.true_gear_connection.max_write_size())?;
+            buffer.extend(buffer_effect);
+        }
+
+        let stop_names: BTreeSet<&str> = effects
+            .iter()
+            .flat_map(|effect| &effect.tracks)
+            .map(|track| track.stop_name.as_str())
+            .filter(|stop_name| !stop_name.is_empty())
+            .collect();
+        for stop_name in stop_names {
+            // an effect restarting itself must not cancel the rest of this play
+            if let Some(stop_frame) = self.take_stop_frame(Some(stop_name), Some(play_id)).await? {
+                tracing::debug!("Stopping {} before playing", stop_name);
+                buffer.splice(0..0, stop_frame);
+            }
+        }
+        // a preempted effect only loses what it plays now; its later windows
+        // weigh their priority again when they are due
+        for (&preempted_id, name) in &preempted {
+            let matches = |footprint: &EffectFootprint| footprint.play_id == Some(preempted_id);
+            if let Some(silence_frame) = self.take_silence_frame(name, false, matches).await? {
+                tracing::debug!("Silencing {} before playing", name);
+                buffer.splice(0..0, silence_frame);
+            }
+        }
+
+        tracing::debug!("Sending message bytes ({}): {:02X?}", buffer.len(), buffer);
+
+        let frame_size = buffer.len();
+        let footprints = effects
+            .iter()
+            .map(|effect| EffectFootprint {
+                play_id: Some(play_id),
+                ..EffectFootprint::of(effect)
+            })
+            .collect();
+        let written = self.send_or_queue(buffer, footprints).await?;
+        Ok(Submitted {
+            frame_size: Some(frame_size),
+            written,
+        })
+    }
+
+    pub fn subscribe_device_status(
+        &self,
+    ) -> broadcast::Receiver<ble_notify_parser::DeviceStatusEvent> {
+        self.ble_notify_parser.subscribe_device_status()
+    }
+
+    pub fn device_status(&self) -> Option<ble_notify_parser::DeviceStatusEvent> {
+        self.ble_notify_parser.device_status()
     }
 
     #[allow(dead_code)]
@@ -50,49 +945,372 @@ impl TrueGearBLEController {
         self.electical_effect_ratio
     }
 
+    #[allow(dead_code)]
     pub fn set_electical_effect_ratio(&mut self, ratio: f32) {
         self.electical_effect_ratio = ratio;
     }
 
-    pub async fn start(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pub async fn start(&mut self) -> Result<(), TrueGearError> {
         self.true_gear_connection.start().await
     }
 
     #[allow(dead_code)]
-    pub async fn auto_connect(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        self.true_gear_connection.auto_connect().await
+    pub async fn connect(&mut self) -> Result<(), TrueGearError> {
+        self.true_gear_connection.connect().await
+    }
+
+    pub async fn connection_state(&self) -> ConnectionState {
+        self.true_gear_connection.connection_state().await
+    }
+
+    pub fn subscribe_connection_state(&self) -> broadcast::Receiver<ConnectionState> {
+        self.true_gear_connection.subscribe_connection_state()
     }
 
-    pub async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pub async fn close(&mut self) -> Result<(), TrueGearError> {
         self.true_gear_connection.disconnect().await
     }
 
     pub async fn send_ble_messages(
         &mut self,
         messages: &[true_gear_message::Message],
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut buffer: Vec<u8> = Vec::new();
-
-        for message in &mut messages.iter() {
-            let mut buffer_effect: Vec<u8> = Vec::new();
-            message.write_ble_bytes_to(&mut buffer_effect, self.electical_effect_ratio)?;
-            buffer.extend(buffer_effect);
-        }
+    ) -> Result<(), TrueGearError> {
+        let effects: Vec<&Effect> = messages.iter().map(|message| &message.body).collect();
+        let play_id = self.new_play_id();
+        self.play(&effects, BTreeMap::new(), play_id)
+            .await?
+            .written()
+            .await?;
+        Ok(())
+    }
 
-        tracing::debug!("Sending message bytes ({}): {:02X?}", buffer.len(), buffer);
+    /// Sends `message` subject to its priority, returning the size of the
+    /// frame written (or queued), or `None` if it was deferred.
+    pub async fn send_ble_message(
+        &mut self,
+        message: true_gear_message::Message,
+    ) -> Result<Option<usize>, TrueGearError> {
+        self.submit_ble_message(message).await?.written().await
+    }
 
-        self.true_gear_connection.send_data(&buffer).await
+    /// Hands `message` over like `send_ble_message`, but returns before the
+    /// write it goes out in when that waits for the coalescing window, so
+    /// that the next effect can join the same write.
+    pub async fn submit_ble_message(
+        &mut self,
+        message: true_gear_message::Message,
+    ) -> Result<Submitted, TrueGearError> {
+        let defer_deadline = Instant::now() + self.priority_defer_max;
+        self.submit(message.body, defer_deadline).await
     }
 
-    pub async fn send_ble_message(
+    /// Hands `message` over like `submit_ble_message` once `play_at` comes,
+    /// or straight away if it has passed. A stop before then cancels it.
+    pub async fn submit_ble_message_at(
         &mut self,
         message: true_gear_message::Message,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut buffer: Vec<u8> = Vec::new();
-        message.write_ble_bytes_to(&mut buffer, self.electical_effect_ratio)?;
+        play_at: Instant,
+    ) -> Result<Submitted, TrueGearError> {
+        if play_at <= Instant::now() {
+            return self.submit_ble_message(message).await;
+        }
 
-        tracing::debug!("Sending message bytes ({}): {:02X?}", buffer.len(), buffer);
+        // validate now so the sender hears about a bad effect
+        let effect = message.body;
+        effect.validate()?;
+
+        let id = self.new_play_id();
+        tracing::debug!(
+            "Scheduling {} in {:?}",
+            effect.name,
+            play_at.saturating_duration_since(Instant::now())
+        );
+        self.scheduled_effects.lock().await.push(ScheduledEffect {
+            id,
+            footprint: EffectFootprint::of(&effect),
+        });
+
+        let mut controller = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(play_at.into()).await;
+            if !controller.unschedule(id).await {
+                return;
+            }
+
+            let defer_deadline = play_at + controller.priority_defer_max;
+            let result = match controller.submit(effect, defer_deadline).await {
+                Ok(submitted) => submitted.written().await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) => {}
+                Err(e @ TrueGearError::LowerPriority { .. }) => tracing::info!("{}", e),
+                Err(e) => tracing::warn!("Failed to play a scheduled effect: {}", e),
+            }
+        });
+
+        Ok(Submitted::done(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_transport::MockTransport;
+
+    fn options() -> ControllerOptions {
+        ControllerOptions {
+            electical_effect_ratio: 1.0,
+            pending_effect_capacity: 4,
+            pending_effect_ttl: Duration::from_secs(2),
+            priority_policy: PriorityPolicy::Drop,
+            priority_defer_max: Duration::from_secs(2),
+            coalesce_window: Duration::ZERO,
+        }
+    }
+
+    fn message(name: &str, index: Vec<u8>) -> true_gear_message::Message {
+        true_gear_message::Message {
+            method: "play_no_registered".into(),
+            body: Effect {
+                name: name.into(),
+                uuid: name.into(),
+                keep: false,
+                priority: 0,
+                tracks: vec![Track {
+                    start_time: 0,
+                    end_time: 100,
+                    stop_name: "".into(),
+                    start_intensity: 50,
+                    end_intensity: 50,
+                    intensity_mode: IntensityMode::Const,
+                    action_type: ActionType::Shake,
+                    once: false,
+                    interval: 0,
+                    index,
+                }],
+                repeat: 1,
+            },
+        }
+    }
+
+    /// `68 68 01 | 01 00 0000 0064 32 32 | dot 0 | 16`
+    const DOT_0_FRAME: [u8; 20] = [
+        0x68, 0x68, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x64, 0x32, 0x32, 0x80, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x16,
+    ];
+
+    fn connected_frame() -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for message in predefined::on_connected_message() {
+            message.body.write_ble_bytes_to(&mut buffer, 1.0).unwrap();
+        }
+        buffer
+    }
+
+    /// Waits for the frames written by tasks the controller spawned.
+    async fn wait_for_frames(transport: &MockTransport, count: usize) -> Vec<Vec<u8>> {
+        for _ in 0..100 {
+            if transport.written_frames().await.len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        transport.take_written_frames().await
+    }
+
+    async fn connected_controller(
+        options: ControllerOptions,
+    ) -> (TrueGearBLEController<MockTransport>, MockTransport) {
+        let transport = MockTransport::new();
+        let mut controller = TrueGearBLEController::build(transport.clone(), options).await;
+        controller.start().await.unwrap();
+        assert_eq!(wait_for_frames(&transport, 1).await, [connected_frame()]);
+        (controller, transport)
+    }
+
+    #[tokio::test]
+    async fn writes_effect_bytes() {
+        let (mut controller, transport) = connected_controller(options()).await;
+
+        let frame_size = controller
+            .send_ble_message(message("hit", vec![0]))
+            .await
+            .unwrap();
+
+        assert_eq!(frame_size, Some(DOT_0_FRAME.len()));
+        assert_eq!(transport.take_written_frames().await, [DOT_0_FRAME]);
+    }
+
+    #[tokio::test]
+    async fn coalesces_effects_into_one_write() {
+        let options = ControllerOptions {
+            coalesce_window: Duration::from_millis(20),
+            ..options()
+        };
+        let (mut controller, transport) = connected_controller(options).await;
+
+        let first = controller
+            .submit_ble_message(message("hit", vec![0]))
+            .await
+            .unwrap();
+        let second = controller
+            .submit_ble_message(message("hit", vec![1]))
+            .await
+            .unwrap();
+        assert!(first.is_waiting() && second.is_waiting());
+        assert!(transport.written_frames().await.is_empty());
+
+        assert_eq!(first.written().await.unwrap(), Some(DOT_0_FRAME.len()));
+        assert_eq!(second.written().await.unwrap(), Some(DOT_0_FRAME.len()));
+        let frames = transport.take_written_frames().await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][..DOT_0_FRAME.len()], DOT_0_FRAME);
+    }
+
+    #[tokio::test]
+    async fn reports_failed_coalesced_write_to_every_effect() {
+        let options = ControllerOptions {
+            coalesce_window: Duration::from_millis(20),
+            pending_effect_capacity: 0,
+            ..options()
+        };
+        let mut controller = TrueGearBLEController::build(MockTransport::new(), options).await;
+
+        let first = controller
+            .submit_ble_message(message("hit", vec![0]))
+            .await
+            .unwrap();
+        // waits for the write it shares with the first effect
+        let second = controller.send_ble_message(message("hit", vec![1])).await;
+
+        assert!(matches!(second, Err(TrueGearError::NotConnected)));
+        assert!(matches!(
+            first.written().await,
+            Err(TrueGearError::NotConnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn queues_effects_until_connected() {
+        let transport = MockTransport::new();
+        let mut controller = TrueGearBLEController::build(transport.clone(), options()).await;
+
+        controller
+            .send_ble_message(message("hit", vec![0]))
+            .await
+            .unwrap();
+        assert!(transport.written_frames().await.is_empty());
+
+        controller.start().await.unwrap();
+        assert_eq!(
+            wait_for_frames(&transport, 2).await,
+            [connected_frame(), DOT_0_FRAME.to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_injected_status() {
+        let (controller, transport) = connected_controller(options()).await;
+        let mut receiver = controller.subscribe_device_status();
+
+        // status split across two notifications
+        let status = [
+            0x68, 0x68, 0x01, 0x81, 0x02, 0x03, 0x04, 0x02, 0x07, 0x0F, 0xA0, 0x01, 0x05, 0x0F,
+            0x3C, 0x01, 0x05, 0x00, 0x00, 0x16,
+        ];
+        transport.inject_notification(&status[..5]).await;
+        assert!(receiver.try_recv().is_err());
+        transport.inject_notification(&status[5..]).await;
+
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.main.model, 0x0207);
+        assert_eq!(event.main.battery_mv, 4000);
+        assert_eq!(event.left.battery_mv, 3900);
+        assert_eq!(
+            event.right.state,
+            ble_notify_parser::ModuleState::DetachedOrCharging
+        );
+        assert_eq!(controller.device_status(), Some(event));
+    }
+
+    #[tokio::test]
+    async fn stop_silences_playing_effect() {
+        let (mut controller, transport) = connected_controller(options()).await;
+        controller
+            .send_ble_message(message("hit", vec![0]))
+            .await
+            .unwrap();
+        transport.take_written_frames().await;
+
+        assert!(controller.stop(Some("hit")).await.unwrap().is_some());
+        let frames = transport.take_written_frames().await;
+        assert_eq!(frames.len(), 1);
+        // a keep Const track at zero intensity on dot 0
+        assert_eq!(frames[0][..4], [0x68, 0x68, 0x01, 0x03]);
+        assert_eq!(frames[0][9..12], [0x00, 0x00, 0x80]);
+
+        assert_eq!(controller.stop(Some("hit")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn preemption_leaves_schedule_running() {
+        let (mut controller, transport) = connected_controller(options()).await;
+        let mut looping = message("loop", vec![0]);
+        looping.body.repeat = 0;
+        controller.send_ble_message(looping).await.unwrap();
+
+        let mut hit = message("hit", vec![0]);
+        hit.body.priority = 1;
+        controller.send_ble_message(hit).await.unwrap();
+        let frames = transport.take_written_frames().await;
+        // the loop's first window, then the hit after a frame silencing it
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1][..4], [0x68, 0x68, 0x01, 0x03]);
+        assert_eq!(
+            frames[1][frames[1].len() - DOT_0_FRAME.len()..],
+            DOT_0_FRAME
+        );
+
+        // the loop carries on once the hit is over
+        assert!(!wait_for_frames(&transport, 1).await.is_empty());
+        assert!(controller.stop(Some("loop")).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn looping_effect_can_stop_itself_by_name() {
+        let (mut controller, transport) = connected_controller(options()).await;
+        let mut looping = message("loop", vec![0]);
+        looping.body.repeat = 0;
+        looping.body.tracks[0].stop_name = "loop".into();
+        controller.send_ble_message(looping).await.unwrap();
+        assert_eq!(transport.take_written_frames().await.len(), 1);
+
+        // stopping itself as it starts does not end the loop
+        assert!(wait_for_frames(&transport, 2).await.len() >= 2);
+        assert!(controller.stop(Some("loop")).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn schedule_keeps_other_plays_of_its_name() {
+        let (mut controller, _transport) = connected_controller(options()).await;
+        let mut looping = message("hit", vec![0]);
+        looping.body.repeat = 0;
+        controller.send_ble_message(looping).await.unwrap();
+
+        let mut held = message("hit", vec![1]);
+        held.body.priority = 2;
+        held.body.tracks[0].end_time = 2000;
+        controller.send_ble_message(held).await.unwrap();
 
-        self.true_gear_connection.send_data(&buffer).await
+        // a few windows of the loop later, the other hit still holds dot 1
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let mut shot = message("shot", vec![1]);
+        shot.body.priority = 1;
+        assert!(matches!(
+            controller.send_ble_message(shot).await,
+            Err(TrueGearError::LowerPriority { .. })
+        ));
+        controller.stop(None).await.unwrap();
     }
 }
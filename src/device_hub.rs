@@ -0,0 +1,64 @@
+use crate::controller::TrueGearBLEController;
+use crate::error::TrueGearError;
+use crate::transport::TrueGearTransport;
+use std::sync::Arc;
+
+/// Alias of the only device when none is configured explicitly.
+pub const DEFAULT_DEVICE_ALIAS: &str = "default";
+
+/// A vest driven by the server, identified by a stable alias.
+#[derive(Clone)]
+pub struct Device<T: TrueGearTransport> {
+    pub alias: String,
+    pub controller: TrueGearBLEController<T>,
+}
+
+/// The set of vests driven by the server.
+#[derive(Clone)]
+pub struct DeviceHub<T: TrueGearTransport> {
+    devices: Arc<Vec<Device<T>>>,
+}
+
+impl<T: TrueGearTransport> DeviceHub<T> {
+    pub fn new(devices: Vec<Device<T>>) -> Self {
+        DeviceHub {
+            devices: Arc::new(devices),
+        }
+    }
+
+    pub fn devices(&self) -> &[Device<T>] {
+        &self.devices
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&Device<T>> {
+        self.devices.iter().find(|device| device.alias == alias)
+    }
+
+    /// The devices a request addresses: the one named by `alias`, or every
+    /// device if `alias` is `None`.
+    pub fn select(&self, alias: Option<&str>) -> Result<Vec<Device<T>>, TrueGearError> {
+        match alias {
+            Some(alias) => match self.get(alias) {
+                Some(device) => Ok(vec![device.clone()]),
+                None => Err(TrueGearError::UnknownDevice(alias.to_string())),
+            },
+            None => Ok(self.devices.to_vec()),
+        }
+    }
+
+    pub async fn start(&self) -> Result<(), TrueGearError> {
+        for device in self.devices.iter() {
+            device.controller.clone().start().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn close(&self) -> Result<(), TrueGearError> {
+        for device in self.devices.iter() {
+            if let Err(e) = device.controller.clone().close().await {
+                tracing::error!("Failed to close device {}: {}", device.alias, e);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,503 @@
+use crate::error::TrueGearError;
+use crate::true_gear_message::Effect;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often a watched library checks its files for changes.
+pub const LIBRARY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+struct LibraryFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    /// The last version of the file that loaded, if any did.
+    effect: Option<Arc<Effect>>,
+}
+
+/// Effects loaded from the JSON files (see `doc/effect.schema.json`) under
+/// the library directories, looked up by name or uuid.
+#[derive(Debug, Default)]
+pub struct EffectLibrary {
+    dirs: Vec<PathBuf>,
+    /// Every effect file found, in loading order.
+    files: Vec<LibraryFile>,
+    effects: HashMap<String, Arc<Effect>>,
+    /// The file each effect was loaded from, by effect name.
+    sources: HashMap<String, PathBuf>,
+}
+
+/// The effect names that differ between two versions of a library.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LibraryChanges {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl LibraryChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl EffectLibrary {
+    /// Loads every `.json` file under `dirs`, including subdirectories.
+    ///
+    /// Files that cannot be loaded are reported and skipped, as are effects
+    /// whose name is already taken by an earlier file. Fails only if one of
+    /// `dirs` cannot be read.
+    pub fn load(dirs: &[PathBuf]) -> Result<EffectLibrary, TrueGearError> {
+        let library = EffectLibrary {
+            dirs: dirs.to_vec(),
+            ..Default::default()
+        };
+        if dirs.is_empty() {
+            return Ok(library);
+        }
+
+        let files = library.scan()?.unwrap_or_default();
+        let library = library.with_files(files);
+
+        tracing::info!("Loaded {} effects from the library", library.len());
+
+        Ok(library)
+    }
+
+    /// A new version of the library if any of its files was added, modified
+    /// or removed, along with the effects that changed (possibly none).
+    ///
+    /// Only added and modified files are parsed again. A modified file that
+    /// no longer loads is reported and keeps its last good effect.
+    pub fn reload(&self) -> Result<Option<(EffectLibrary, LibraryChanges)>, TrueGearError> {
+        let Some(files) = self.scan()? else {
+            return Ok(None);
+        };
+
+        let library = EffectLibrary {
+            dirs: self.dirs.clone(),
+            ..Default::default()
+        }
+        .with_files(files);
+        let changes = self.changes_to(&library);
+
+        if changes.is_empty() {
+            return Ok(Some((library, changes)));
+        }
+
+        tracing::info!(
+            "Reloaded the effect library: {} added, {} changed, {} removed",
+            changes.added.len(),
+            changes.changed.len(),
+            changes.removed.len()
+        );
+
+        Ok(Some((library, changes)))
+    }
+
+    /// The effect files under the library directories, or `None` if they
+    /// are the same as the ones already loaded.
+    fn scan(&self) -> Result<Option<Vec<LibraryFile>>, TrueGearError> {
+        let mut paths = Vec::new();
+        for dir in &self.dirs {
+            effect_files(dir, &mut paths).map_err(|e| {
+                TrueGearError::Io(std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to read effect library {}: {}", dir.display(), e),
+                ))
+            })?;
+        }
+
+        let loaded: HashMap<&Path, &LibraryFile> = self
+            .files
+            .iter()
+            .map(|file| (file.path.as_path(), file))
+            .collect();
+        let mut changed = paths.len() != self.files.len();
+
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let modified = std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            let previous = loaded.get(path.as_path());
+
+            let effect = match previous {
+                Some(previous) if previous.modified == modified => previous.effect.clone(),
+                _ => {
+                    changed = true;
+                    match load_effect_file(&path) {
+                        Ok(effect) => Some(Arc::new(effect)),
+                        Err(e) => {
+                            tracing::warn!("Skipping effect file {}", e);
+                            previous.and_then(|previous| previous.effect.clone())
+                        }
+                    }
+                }
+            };
+
+            files.push(LibraryFile {
+                path,
+                modified,
+                effect,
+            });
+        }
+
+        Ok(changed.then_some(files))
+    }
+
+    fn with_files(mut self, files: Vec<LibraryFile>) -> EffectLibrary {
+        for file in &files {
+            if let Some(effect) = &file.effect {
+                self.insert(&file.path, effect.clone());
+            }
+        }
+        self.files = files;
+        self
+    }
+
+    fn insert(&mut self, path: &Path, effect: Arc<Effect>) {
+        if let Some(other) = self.sources.get(&effect.name) {
+            tracing::warn!(
+                "Skipping effect file {}: effect {} is already defined in {}",
+                path.display(),
+                effect.name,
+                other.display()
+            );
+            return;
+        }
+
+        self.sources.insert(effect.name.clone(), path.to_path_buf());
+        self.effects
+            .entry(effect.uuid.clone())
+            .or_insert_with(|| effect.clone());
+        self.effects.insert(effect.name.clone(), effect);
+    }
+
+    fn changes_to(&self, other: &EffectLibrary) -> LibraryChanges {
+        let mut changes = LibraryChanges::default();
+        for name in other.sources.keys() {
+            match (self.effects.get(name), other.effects.get(name)) {
+                (Some(effect), Some(other_effect)) if self.sources.contains_key(name) => {
+                    if !Arc::ptr_eq(effect, other_effect) {
+                        changes.changed.push(name.clone());
+                    }
+                }
+                _ => changes.added.push(name.clone()),
+            }
+        }
+        for name in self.sources.keys() {
+            if !other.sources.contains_key(name) {
+                changes.removed.push(name.clone());
+            }
+        }
+        changes.added.sort();
+        changes.changed.sort();
+        changes.removed.sort();
+        changes
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<Effect>> {
+        self.effects.get(key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+/// Collects the `.json` files under `dir`, sorted so that loading is
+/// repeatable.
+fn effect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            effect_files(&path, paths)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+        {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads and validates the effect in `path`. Errors start with the path, and
+/// JSON errors end with the line and column.
+fn load_effect_file(path: &Path) -> Result<Effect, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let effect =
+        serde_json::from_str::<Effect>(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    effect
+        .validate()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    Ok(effect)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+    /// A directory under the system temp dir, removed when dropped.
+    pub(crate) struct TestDir(pub(crate) PathBuf);
+
+    impl TestDir {
+        pub(crate) fn new() -> Self {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "truegear-library-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+
+        /// Writes `text` to `name`, creating its parent directories. Every
+        /// write gets a later modification time, however quickly they follow
+        /// each other.
+        pub(crate) fn write(&self, name: &str, text: &str) -> PathBuf {
+            static MODIFIED: AtomicU64 = AtomicU64::new(1);
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, text).unwrap();
+            let modified = Duration::from_secs(MODIFIED.fetch_add(1, Ordering::Relaxed));
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::UNIX_EPOCH + modified))
+                .unwrap();
+            path
+        }
+
+        fn remove(&self, name: &str) {
+            std::fs::remove_file(self.0.join(name)).unwrap();
+        }
+
+        pub(crate) fn load(&self) -> EffectLibrary {
+            EffectLibrary::load(std::slice::from_ref(&self.0)).unwrap()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(crate) fn effect_json(name: &str, uuid: &str, intensity: u16) -> String {
+        format!(
+            r#"{{
+  "name": "{name}",
+  "uuid": "{uuid}",
+  "keep": "False",
+  "priority": 0,
+  "tracks": [
+    {{
+      "start_time": 0,
+      "end_time": 100,
+      "stop_name": "",
+      "start_intensity": {intensity},
+      "end_intensity": {intensity},
+      "intensity_mode": "Const",
+      "action_type": "Shake",
+      "once": "False",
+      "interval": 0,
+      "index": [0]
+    }}
+  ]
+}}"#
+        )
+    }
+
+    pub(crate) fn intensity(library: &EffectLibrary, key: &str) -> Option<u16> {
+        library
+            .get(key)
+            .map(|effect| effect.tracks[0].start_intensity)
+    }
+
+    #[test]
+    fn loads_effects_by_name_and_uuid() {
+        let dir = TestDir::new();
+        dir.write("hit.json", &effect_json("hit", "hit-uuid", 10));
+        dir.write(
+            "nested/deeper/shot.JSON",
+            &effect_json("shot", "shot-uuid", 20),
+        );
+
+        let library = dir.load();
+        assert_eq!(library.len(), 2);
+        assert_eq!(intensity(&library, "hit"), Some(10));
+        assert_eq!(intensity(&library, "hit-uuid"), Some(10));
+        assert_eq!(intensity(&library, "shot-uuid"), Some(20));
+    }
+
+    #[test]
+    fn skips_files_that_are_not_json() {
+        let dir = TestDir::new();
+        dir.write("hit.json", &effect_json("hit", "hit", 10));
+        dir.write("notes.txt", &effect_json("notes", "notes", 10));
+        dir.write("hit.json.bak", &effect_json("backup", "backup", 10));
+        dir.write("README", "not an effect");
+
+        let library = dir.load();
+        assert_eq!(library.len(), 1);
+        assert!(library.get("notes").is_none());
+        assert!(library.get("backup").is_none());
+    }
+
+    #[test]
+    fn first_file_to_define_a_name_wins() {
+        let first = TestDir::new();
+        // subdirectories are loaded in place, by their sorted path
+        first.write("a/hit.json", &effect_json("hit", "a", 1));
+        first.write("b.json", &effect_json("hit", "b", 2));
+        first.write("c.json", &effect_json("shot", "c", 3));
+        let second = TestDir::new();
+        second.write("a.json", &effect_json("shot", "d", 4));
+        second.write("b.json", &effect_json("kick", "e", 5));
+
+        let library = EffectLibrary::load(&[first.0.clone(), second.0.clone()]).unwrap();
+        assert_eq!(library.len(), 3);
+        assert_eq!(intensity(&library, "hit"), Some(1));
+        // the earlier directory wins over the later one
+        assert_eq!(intensity(&library, "shot"), Some(3));
+        assert_eq!(intensity(&library, "kick"), Some(5));
+        // the losing files' uuids are not looked up either
+        assert!(library.get("b").is_none());
+        assert!(library.get("d").is_none());
+    }
+
+    #[test]
+    fn skips_broken_files_and_fails_on_missing_dirs() {
+        let dir = TestDir::new();
+        dir.write(
+            "broken.json",
+            "{\n  \"name\": \"broken\",\n  \"uuid\": 7\n}",
+        );
+        dir.write("hit.json", &effect_json("hit", "hit", 10));
+
+        let library = dir.load();
+        assert_eq!(library.len(), 1);
+
+        let missing = dir.0.join("missing");
+        let error = EffectLibrary::load(std::slice::from_ref(&missing)).unwrap_err();
+        assert!(error.to_string().contains(&missing.display().to_string()));
+    }
+
+    #[test]
+    fn reports_path_and_position_of_malformed_file() {
+        let dir = TestDir::new();
+        let path = dir.write(
+            "broken.json",
+            "{\n  \"name\": \"broken\",\n  \"uuid\": 7\n}",
+        );
+
+        let error = load_effect_file(&path).unwrap_err();
+        assert!(error.starts_with(&format!("{}: ", path.display())));
+        assert!(error.ends_with("at line 3 column 11"), "{}", error);
+
+        let path = dir.write("invalid.json", &effect_json("invalid", "invalid", 200));
+        let error = load_effect_file(&path).unwrap_err();
+        assert!(error.starts_with(&format!("{}: ", path.display())));
+        assert!(error.contains("start_intensity"), "{}", error);
+    }
+
+    /// Reloads `library`, expecting its files to have changed.
+    fn reload(library: &EffectLibrary) -> (EffectLibrary, LibraryChanges) {
+        library
+            .reload()
+            .unwrap()
+            .expect("the library files changed")
+    }
+
+    #[test]
+    fn reload_ignores_untouched_files() {
+        let dir = TestDir::new();
+        dir.write("hit.json", &effect_json("hit", "hit", 10));
+
+        let library = dir.load();
+        assert!(library.reload().unwrap().is_none());
+    }
+
+    #[test]
+    fn reload_lists_added_changed_and_removed_effects() {
+        let dir = TestDir::new();
+        dir.write("hit.json", &effect_json("hit", "hit", 10));
+        dir.write("shot.json", &effect_json("shot", "shot", 20));
+        dir.write("kick.json", &effect_json("kick", "kick", 30));
+        dir.write("rename.json", &effect_json("before", "before", 40));
+        let library = dir.load();
+
+        dir.write("hit.json", &effect_json("hit", "hit", 15));
+        dir.remove("shot.json");
+        dir.write("punch.json", &effect_json("punch", "punch", 50));
+        dir.write("rename.json", &effect_json("after", "after", 40));
+        let (library, changes) = reload(&library);
+
+        assert_eq!(changes.added, ["after", "punch"]);
+        assert_eq!(changes.changed, ["hit"]);
+        assert_eq!(changes.removed, ["before", "shot"]);
+        assert_eq!(intensity(&library, "hit"), Some(15));
+        assert_eq!(intensity(&library, "kick"), Some(30));
+        assert_eq!(intensity(&library, "punch"), Some(50));
+        assert!(library.get("shot").is_none());
+        assert!(library.get("before").is_none());
+        assert!(library.reload().unwrap().is_none());
+    }
+
+    #[test]
+    fn reload_keeps_last_good_version_of_broken_file() {
+        let dir = TestDir::new();
+        dir.write("hit.json", &effect_json("hit", "hit", 10));
+        let library = dir.load();
+
+        dir.write("hit.json", "{ \"name\": \"hit\",");
+        let (library, changes) = reload(&library);
+        assert!(changes.is_empty());
+        assert_eq!(intensity(&library, "hit"), Some(10));
+
+        // fixing it picks up the edit
+        dir.write("hit.json", &effect_json("hit", "hit", 12));
+        let (library, changes) = reload(&library);
+        assert_eq!(changes.changed, ["hit"]);
+        assert_eq!(intensity(&library, "hit"), Some(12));
+    }
+
+    #[test]
+    fn reload_forgets_removed_file() {
+        let dir = TestDir::new();
+        dir.write("a.json", &effect_json("hit", "hit", 1));
+        dir.write("b.json", &effect_json("hit", "hit", 2));
+        dir.write("c.json", &effect_json("shot", "shot", 3));
+        let library = dir.load();
+        assert_eq!(intensity(&library, "hit"), Some(1));
+
+        // the file it shadowed takes over the name
+        dir.remove("a.json");
+        let (library, changes) = reload(&library);
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        assert_eq!(changes.changed, ["hit"]);
+        assert_eq!(intensity(&library, "hit"), Some(2));
+
+        // a broken file that is then removed takes its last good effect along
+        dir.write("c.json", "not json");
+        let (library, _) = reload(&library);
+        dir.remove("c.json");
+        let (library, changes) = reload(&library);
+        assert_eq!(changes.removed, ["shot"]);
+        assert!(library.get("shot").is_none());
+        assert_eq!(library.len(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::true_gear_message::Effect;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Where a registered effect can be played from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegisterScope {
+    /// Only the connection that registered it, until it disconnects.
+    #[default]
+    Connection,
+    /// Every connection, until the server stops.
+    Global,
+}
+
+/// Effects registered by WebSocket clients, looked up by name or uuid.
+#[derive(Debug, Default)]
+pub struct EffectRegistry {
+    effects: HashMap<String, Arc<Effect>>,
+}
+
+impl EffectRegistry {
+    /// Stores `effect` under its name and uuid, replacing any effect
+    /// registered under either before.
+    pub fn register(&mut self, effect: Effect) {
+        for key in [&effect.name, &effect.uuid] {
+            if let Some(replaced) = self.effects.remove(key) {
+                for other_key in [&replaced.name, &replaced.uuid] {
+                    if self
+                        .effects
+                        .get(other_key)
+                        .is_some_and(|e| Arc::ptr_eq(e, &replaced))
+                    {
+                        self.effects.remove(other_key);
+                    }
+                }
+            }
+        }
+
+        let effect = Arc::new(effect);
+        self.effects.insert(effect.uuid.clone(), effect.clone());
+        self.effects.insert(effect.name.clone(), effect);
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<Effect>> {
+        self.effects.get(key).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::true_gear_message::{ActionType, IntensityMode, Track};
+
+    fn effect(name: &str, uuid: &str, intensity: u16) -> Effect {
+        Effect {
+            name: name.into(),
+            uuid: uuid.into(),
+            keep: false,
+            priority: 0,
+            tracks: vec![Track {
+                start_time: 0,
+                end_time: 100,
+                stop_name: "".into(),
+                start_intensity: intensity,
+                end_intensity: intensity,
+                intensity_mode: IntensityMode::Const,
+                action_type: ActionType::Shake,
+                once: false,
+                interval: 0,
+                index: vec![0],
+            }],
+            repeat: 1,
+        }
+    }
+
+    fn intensity(registry: &EffectRegistry, key: &str) -> Option<u16> {
+        registry
+            .get(key)
+            .map(|effect| effect.tracks[0].start_intensity)
+    }
+
+    #[test]
+    fn finds_effect_by_name_and_uuid() {
+        let mut registry = EffectRegistry::default();
+        registry.register(effect("hit", "hit-uuid", 10));
+
+        assert_eq!(intensity(&registry, "hit"), Some(10));
+        assert_eq!(intensity(&registry, "hit-uuid"), Some(10));
+        assert_eq!(intensity(&registry, "shot"), None);
+    }
+
+    #[test]
+    fn replaces_effect_with_same_name() {
+        let mut registry = EffectRegistry::default();
+        registry.register(effect("hit", "old-uuid", 10));
+        registry.register(effect("hit", "new-uuid", 20));
+
+        assert_eq!(intensity(&registry, "hit"), Some(20));
+        assert_eq!(intensity(&registry, "new-uuid"), Some(20));
+        // the replaced effect is gone under its uuid as well
+        assert_eq!(intensity(&registry, "old-uuid"), None);
+    }
+
+    #[test]
+    fn replaces_effect_with_same_uuid() {
+        let mut registry = EffectRegistry::default();
+        registry.register(effect("old", "uuid", 10));
+        registry.register(effect("new", "uuid", 20));
+
+        assert_eq!(intensity(&registry, "uuid"), Some(20));
+        assert_eq!(intensity(&registry, "new"), Some(20));
+        assert_eq!(intensity(&registry, "old"), None);
+    }
+
+    #[test]
+    fn keeps_effects_that_only_share_a_key_with_the_replaced_one() {
+        let mut registry = EffectRegistry::default();
+        // registered under a name that another effect uses as its uuid
+        registry.register(effect("a", "b", 10));
+        registry.register(effect("b", "c", 20));
+        assert_eq!(intensity(&registry, "a"), None);
+        assert_eq!(intensity(&registry, "b"), Some(20));
+
+        registry.register(effect("d", "d", 30));
+        registry.register(effect("e", "c", 40));
+        assert_eq!(intensity(&registry, "d"), Some(30));
+        assert_eq!(intensity(&registry, "b"), None);
+        assert_eq!(intensity(&registry, "c"), Some(40));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::effect_validation::MAX_INTENSITY;
+use crate::error::TrueGearError;
+use crate::true_gear_message::Effect;
+
+impl Effect {
+    /// A copy of the effect with every intensity multiplied by `intensity`
+    /// (capped at the maximum) and every track time by `duration`.
+    pub fn scaled(&self, intensity: f32, duration: f32) -> Result<Effect, TrueGearError> {
+        let mut issues = Vec::new();
+        if !(intensity.is_finite() && intensity >= 0.0) {
+            issues.push(format!("intensity: invalid factor {}", intensity));
+        }
+        if !(duration.is_finite() && duration > 0.0) {
+            issues.push(format!("duration: invalid factor {}", duration));
+        }
+        if !issues.is_empty() {
+            return Err(TrueGearError::Validation(issues));
+        }
+
+        let mut effect = self.clone();
+        for (position, track) in effect.tracks.iter_mut().enumerate() {
+            for value in [&mut track.start_intensity, &mut track.end_intensity] {
+                *value = ((*value as f32 * intensity).round() as u16).min(MAX_INTENSITY);
+            }
+
+            for (field, value) in [
+                ("start_time", &mut track.start_time),
+                ("end_time", &mut track.end_time),
+            ] {
+                let scaled = (*value as f32 * duration).round();
+                if scaled > u32::MAX as f32 {
+                    issues.push(format!(
+                        "tracks[{}].{}: {} ms is beyond the maximum of {} ms once scaled",
+                        position,
+                        field,
+                        scaled,
+                        u32::MAX
+                    ));
+                } else {
+                    *value = scaled as u32;
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(effect)
+        } else {
+            Err(TrueGearError::Validation(issues))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::TrueGearError;
+    use crate::true_gear_message::{ActionType, Effect, IntensityMode, Track};
+
+    fn effect(start_time: u32, end_time: u32, intensity: u16) -> Effect {
+        Effect {
+            name: "test".into(),
+            uuid: "test".into(),
+            keep: false,
+            priority: 0,
+            tracks: vec![Track {
+                start_time,
+                end_time,
+                stop_name: "".into(),
+                start_intensity: intensity,
+                end_intensity: intensity / 2,
+                intensity_mode: IntensityMode::Fade,
+                action_type: ActionType::Shake,
+                once: false,
+                interval: 0,
+                index: vec![0],
+            }],
+            repeat: 1,
+        }
+    }
+
+    fn issues(result: Result<Effect, TrueGearError>) -> Vec<String> {
+        match result {
+            Err(TrueGearError::Validation(issues)) => issues,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scales_intensity_and_times() {
+        let scaled = effect(100, 300, 60).scaled(0.5, 2.5).unwrap();
+        let track = &scaled.tracks[0];
+        assert_eq!((track.start_intensity, track.end_intensity), (30, 15));
+        assert_eq!((track.start_time, track.end_time), (250, 750));
+
+        assert_eq!(
+            effect(100, 300, 60).scaled(1.0, 1.0).unwrap(),
+            effect(100, 300, 60)
+        );
+    }
+
+    #[test]
+    fn caps_intensity_at_maximum() {
+        let scaled = effect(0, 100, 100).scaled(2.0, 1.0).unwrap();
+        let track = &scaled.tracks[0];
+        assert_eq!((track.start_intensity, track.end_intensity), (150, 100));
+
+        let scaled = effect(0, 100, 150).scaled(1000.0, 1.0).unwrap();
+        assert_eq!(scaled.tracks[0].start_intensity, 150);
+        assert_eq!(scaled.tracks[0].end_intensity, 150);
+    }
+
+    #[test]
+    fn saturates_times_at_u32_limit() {
+        // rounds to exactly 2^32 in f32, which is held at u32::MAX
+        let scaled = effect(0, 4_000_000_000, 50)
+            .scaled(1.0, 1.073_741_8)
+            .unwrap();
+        assert_eq!(scaled.tracks[0].end_time, u32::MAX);
+
+        // anything further out would wrap, and is rejected instead
+        assert_eq!(
+            issues(effect(3_000_000_000, 4_000_000_000, 50).scaled(1.0, 2.0)),
+            [
+                "tracks[0].start_time: 6000000000 ms is beyond the maximum of 4294967295 ms once scaled",
+                "tracks[0].end_time: 8000000000 ms is beyond the maximum of 4294967295 ms once scaled",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_factors() {
+        assert_eq!(
+            issues(effect(0, 100, 50).scaled(-1.0, 0.0)),
+            ["intensity: invalid factor -1", "duration: invalid factor 0"]
+        );
+        assert_eq!(
+            issues(effect(0, 100, 50).scaled(f32::NAN, f32::INFINITY)),
+            [
+                "intensity: invalid factor NaN",
+                "duration: invalid factor inf"
+            ]
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use crate::true_gear_message::{Effect, IntensityMode, Track};
+use std::time::Duration;
+
+/// Length of the windows a scheduled effect is sent in. Well within what a
+/// frame holds, so a stopped effect leaves nothing long queued on the device.
+pub const TIMELINE_WINDOW_MS: u32 = 10_000;
+
+impl Effect {
+    /// When the last track ends, in milliseconds.
+    pub fn length(&self) -> u32 {
+        self.tracks
+            .iter()
+            .map(|track| track.start_time.max(track.end_time))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether the effect is too long for one frame or repeats, and so has to
+    /// be sent window by window.
+    pub fn needs_scheduling(&self) -> bool {
+        self.repeat != 1 || self.length() > u16::MAX as u32
+    }
+
+    /// The windows the effect is played in, each with the time it starts at
+    /// from the start of the effect. Endless for an effect that loops.
+    pub fn windows(&self) -> Windows {
+        Windows {
+            effect: self.clone(),
+            length: self.length(),
+            repetition: 0,
+            from: 0,
+        }
+    }
+
+    /// The part of the effect between `from` and `to`, with times relative
+    /// to `from`, or `None` if no track plays then.
+    fn window(&self, from: u32, to: u32, first: bool) -> Option<Effect> {
+        let last = to == self.length();
+        let tracks: Vec<Track> = self
+            .tracks
+            .iter()
+            .flat_map(|track| track.halves())
+            .filter_map(|track| track.window(from, to, last))
+            .map(|mut track| {
+                // the effects to stop are stopped once, when it starts
+                if !first {
+                    track.stop_name.clear();
+                }
+                track
+            })
+            .collect();
+
+        (!tracks.is_empty()).then(|| Effect {
+            name: self.name.clone(),
+            uuid: self.uuid.clone(),
+            keep: self.keep,
+            priority: self.priority,
+            tracks,
+            repeat: 1,
+        })
+    }
+}
+
+impl Track {
+    /// A FadeInAndOut track as its fade in and fade out, any other track as
+    /// it is.
+    fn halves(&self) -> Vec<Track> {
+        if self.intensity_mode != IntensityMode::FadeInAndOut {
+            return vec![self.clone()];
+        }
+
+        let midpoint = self.fade_in_and_out_midpoint();
+        let fade_in = Track {
+            end_time: midpoint,
+            intensity_mode: IntensityMode::Fade,
+            ..self.clone()
+        };
+        let fade_out = Track {
+            start_time: midpoint,
+            start_intensity: self.end_intensity,
+            end_intensity: self.start_intensity,
+            intensity_mode: IntensityMode::Fade,
+            ..self.clone()
+        };
+        vec![fade_in, fade_out]
+    }
+
+    /// The part of the track between `from` and `to`, relative to `from`.
+    /// Instant tracks belong to the window they start in, `once` tracks only
+    /// fire in it, and the `last` window also takes those at its end.
+    fn window(&self, from: u32, to: u32, last: bool) -> Option<Track> {
+        let starts_within =
+            self.start_time >= from && (self.start_time < to || last && self.start_time == to);
+        let overlaps = !self.once && self.start_time < to && self.end_time > from;
+        if !starts_within && !overlaps {
+            return None;
+        }
+
+        let start_time = self.start_time.max(from);
+        let end_time = self.end_time.min(to).max(start_time);
+        let (start_intensity, end_intensity) = match self.intensity_mode {
+            IntensityMode::Const => (self.start_intensity, self.end_intensity),
+            _ => (self.intensity_at(start_time), self.intensity_at(end_time)),
+        };
+
+        Some(Track {
+            start_time: start_time - from,
+            end_time: end_time - from,
+            start_intensity,
+            end_intensity,
+            ..self.clone()
+        })
+    }
+
+    /// The intensity of a fading track `time` ms into the effect.
+    fn intensity_at(&self, time: u32) -> u16 {
+        if self.end_time <= self.start_time {
+            return self.start_intensity;
+        }
+        let progress = (time - self.start_time) as f64 / (self.end_time - self.start_time) as f64;
+        let change = self.end_intensity as f64 - self.start_intensity as f64;
+        (self.start_intensity as f64 + change * progress).round() as u16
+    }
+}
+
+/// The windows of an effect, see [`Effect::windows`].
+pub struct Windows {
+    effect: Effect,
+    length: u32,
+    repetition: u64,
+    from: u32,
+}
+
+impl Iterator for Windows {
+    type Item = (Duration, Effect);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let repeat = self.effect.repeat as u64;
+            // an instant effect cannot loop
+            if (repeat != 0 && self.repetition >= repeat)
+                || (self.length == 0 && self.repetition > 0)
+            {
+                return None;
+            }
+
+            let from = self.from;
+            let to = from.saturating_add(TIMELINE_WINDOW_MS).min(self.length);
+            let offset = self.repetition * self.length as u64 + from as u64;
+            let first = self.repetition == 0 && from == 0;
+
+            if to >= self.length {
+                self.repetition += 1;
+                self.from = 0;
+            } else {
+                self.from = to;
+            }
+
+            if let Some(window) = self.effect.window(from, to, first) {
+                return Some((Duration::from_millis(offset), window));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::true_gear_message::ActionType;
+
+    fn track(start_time: u32, end_time: u32) -> Track {
+        Track {
+            start_time,
+            end_time,
+            stop_name: "".into(),
+            start_intensity: 50,
+            end_intensity: 50,
+            intensity_mode: IntensityMode::Const,
+            action_type: ActionType::Shake,
+            once: false,
+            interval: 0,
+            index: vec![0],
+        }
+    }
+
+    fn fade(start_time: u32, end_time: u32, start_intensity: u16, end_intensity: u16) -> Track {
+        Track {
+            start_intensity,
+            end_intensity,
+            intensity_mode: IntensityMode::Fade,
+            ..track(start_time, end_time)
+        }
+    }
+
+    fn effect(tracks: Vec<Track>, repeat: u32) -> Effect {
+        Effect {
+            name: "test".into(),
+            uuid: "test".into(),
+            keep: false,
+            priority: 0,
+            tracks,
+            repeat,
+        }
+    }
+
+    /// Each window's offset in ms and its tracks' times.
+    fn timeline(windows: impl Iterator<Item = (Duration, Effect)>) -> Vec<(u64, Vec<(u32, u32)>)> {
+        windows
+            .map(|(offset, window)| {
+                let times = window
+                    .tracks
+                    .iter()
+                    .map(|track| (track.start_time, track.end_time))
+                    .collect();
+                (offset.as_millis() as u64, times)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cuts_at_window_length() {
+        let effect = effect(vec![track(0, 25_000)], 1);
+        assert_eq!(
+            timeline(effect.windows()),
+            [
+                (0, vec![(0, TIMELINE_WINDOW_MS)]),
+                (10_000, vec![(0, TIMELINE_WINDOW_MS)]),
+                (20_000, vec![(0, 5_000)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_fade_in_and_out_into_halves() {
+        let fade_in_and_out = Track {
+            intensity_mode: IntensityMode::FadeInAndOut,
+            ..fade(0, 1_000, 10, 100)
+        };
+        assert_eq!(
+            fade_in_and_out.halves(),
+            [fade(0, 500, 10, 100), fade(500, 1_000, 100, 10)]
+        );
+
+        let constant = track(0, 1_000);
+        assert_eq!(constant.halves(), [constant]);
+    }
+
+    #[test]
+    fn interpolates_fade_at_the_cut() {
+        let effect = effect(vec![fade(0, 20_000, 0, 100)], 1);
+        let windows: Vec<Effect> = effect.windows().map(|(_, window)| window).collect();
+        assert_eq!(windows[0].tracks, [fade(0, TIMELINE_WINDOW_MS, 0, 50)]);
+        assert_eq!(windows[1].tracks, [fade(0, TIMELINE_WINDOW_MS, 50, 100)]);
+
+        let track = fade(1_000, 4_000, 0, 150);
+        assert_eq!(track.intensity_at(1_000), 0);
+        assert_eq!(track.intensity_at(2_000), 50);
+        assert_eq!(track.intensity_at(4_000), 150);
+        assert_eq!(fade(1_000, 1_000, 30, 150).intensity_at(1_000), 30);
+    }
+
+    #[test]
+    fn places_once_and_instant_tracks() {
+        let once = Track {
+            once: true,
+            ..track(5_000, 15_000)
+        };
+        let effect = effect(
+            vec![
+                track(0, 20_000),
+                once,
+                track(10_000, 10_000),
+                track(20_000, 20_000),
+            ],
+            1,
+        );
+        assert_eq!(
+            timeline(effect.windows()),
+            [
+                // the once track only fires in the window it starts in
+                (
+                    0,
+                    vec![(0, TIMELINE_WINDOW_MS), (5_000, TIMELINE_WINDOW_MS)]
+                ),
+                // an instant track at a cut belongs to the window after it,
+                // and the last window takes those at its end
+                (
+                    10_000,
+                    vec![
+                        (0, TIMELINE_WINDOW_MS),
+                        (0, 0),
+                        (TIMELINE_WINDOW_MS, TIMELINE_WINDOW_MS)
+                    ]
+                ),
+            ]
+        );
+
+        // a once track that starts in a later window is not sent earlier
+        let late_once = Track {
+            once: true,
+            ..track(12_000, 15_000)
+        };
+        let effect = self::effect(vec![track(0, 20_000), late_once], 1);
+        assert_eq!(
+            timeline(effect.windows()),
+            [
+                (0, vec![(0, TIMELINE_WINDOW_MS)]),
+                (10_000, vec![(0, TIMELINE_WINDOW_MS), (2_000, 5_000)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_stop_name_on_first_window_only() {
+        let effect = effect(
+            vec![Track {
+                stop_name: "other".into(),
+                ..track(0, 15_000)
+            }],
+            2,
+        );
+        let stop_names: Vec<String> = effect
+            .windows()
+            .map(|(_, window)| window.tracks[0].stop_name.clone())
+            .collect();
+        assert_eq!(stop_names, ["other", "", "", ""]);
+    }
+
+    #[test]
+    fn offsets_repeats_and_loops() {
+        let offsets = |effect: &Effect, count: usize| -> Vec<u64> {
+            effect
+                .windows()
+                .take(count)
+                .map(|(offset, _)| offset.as_millis() as u64)
+                .collect()
+        };
+
+        assert_eq!(offsets(&effect(vec![track(0, 100)], 3), 10), [0, 100, 200]);
+        assert_eq!(
+            offsets(&effect(vec![track(0, 15_000)], 2), 10),
+            [0, 10_000, 15_000, 25_000]
+        );
+        // a loop never runs out
+        assert_eq!(
+            offsets(&effect(vec![track(0, 100)], 0), 5),
+            [0, 100, 200, 300, 400]
+        );
+        // an instant effect plays once, whatever its repeat
+        assert_eq!(offsets(&effect(vec![track(0, 0)], 0), 10), [0]);
+    }
+
+    #[test]
+    fn schedules_effects_too_long_for_one_frame() {
+        let max = u16::MAX as u32;
+        assert!(!effect(vec![track(0, max - 1)], 1).needs_scheduling());
+        assert!(!effect(vec![track(0, max)], 1).needs_scheduling());
+        assert!(effect(vec![track(0, max + 1)], 1).needs_scheduling());
+        assert!(effect(vec![track(0, 100)], 2).needs_scheduling());
+        assert!(effect(vec![track(0, 100)], 0).needs_scheduling());
+    }
+}
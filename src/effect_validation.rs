@@ -0,0 +1,212 @@
+use crate::error::TrueGearError;
+use crate::predefined;
+use crate::true_gear_message::{ActionType, Effect, Track};
+
+/// Highest intensity accepted for a track (see `doc/effect.schema.json`).
+pub const MAX_INTENSITY: u16 = 150;
+
+impl Track {
+    fn validation_issues(&self, position: usize, issues: &mut Vec<String>) {
+        let path = format!("tracks[{}]", position);
+
+        if self.index.is_empty() {
+            issues.push(format!("{}.index: no actuators", path));
+        }
+
+        for &index in &self.index {
+            let known = match self.action_type {
+                ActionType::Shake => predefined::shake_flag_shift_map().contains_key(&index),
+                ActionType::Electrical => {
+                    predefined::electrical_flag_shift_map().contains_key(&index)
+                }
+            };
+            if !known {
+                issues.push(format!(
+                    "{}.index: unknown {:?} actuator {}",
+                    path, self.action_type, index
+                ));
+            }
+        }
+
+        if self.start_time > self.end_time {
+            issues.push(format!(
+                "{}: start_time {} is after end_time {}",
+                path, self.start_time, self.end_time
+            ));
+        }
+
+        for (field, intensity) in [
+            ("start_intensity", self.start_intensity),
+            ("end_intensity", self.end_intensity),
+        ] {
+            if intensity > MAX_INTENSITY {
+                issues.push(format!(
+                    "{}.{}: {} is above the maximum of {}",
+                    path, field, intensity, MAX_INTENSITY
+                ));
+            }
+        }
+    }
+}
+
+impl Effect {
+    /// Checks that every track can be encoded as the client meant it,
+    /// listing each problem with the track it was found in.
+    pub fn validate(&self) -> Result<(), TrueGearError> {
+        let mut issues = Vec::new();
+
+        if self.tracks.is_empty() {
+            issues.push("tracks: no tracks".to_string());
+        }
+
+        for (position, track) in self.tracks.iter().enumerate() {
+            track.validation_issues(position, &mut issues);
+        }
+
+        if self.repeat != 1 && !self.tracks.is_empty() && self.length() == 0 {
+            issues.push("repeat: an effect that lasts 0 ms cannot repeat".to_string());
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(TrueGearError::Validation(issues))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::TrueGearError;
+    use crate::true_gear_message::{ActionType, Effect, IntensityMode, Track};
+
+    fn track(action_type: ActionType, index: Vec<u8>) -> Track {
+        Track {
+            start_time: 0,
+            end_time: 100,
+            stop_name: "".into(),
+            start_intensity: 50,
+            end_intensity: 50,
+            intensity_mode: IntensityMode::Const,
+            action_type,
+            once: false,
+            interval: 0,
+            index,
+        }
+    }
+
+    fn effect(tracks: Vec<Track>) -> Effect {
+        Effect {
+            name: "test".into(),
+            uuid: "test".into(),
+            keep: false,
+            priority: 0,
+            tracks,
+            repeat: 1,
+        }
+    }
+
+    fn issues(effect: &Effect) -> Vec<String> {
+        match effect.validate() {
+            Err(TrueGearError::Validation(issues)) => issues,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_effect() {
+        let effect = effect(vec![
+            track(ActionType::Shake, vec![0, 104, 119]),
+            track(ActionType::Electrical, vec![0, 100]),
+        ]);
+        assert!(effect.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_tracks() {
+        assert_eq!(issues(&effect(Vec::new())), ["tracks: no tracks"]);
+    }
+
+    #[test]
+    fn rejects_unknown_index() {
+        let effect = effect(vec![
+            track(ActionType::Shake, vec![0, 99]),
+            track(ActionType::Electrical, vec![1]),
+            track(ActionType::Shake, Vec::new()),
+        ]);
+        assert_eq!(
+            issues(&effect),
+            [
+                "tracks[0].index: unknown Shake actuator 99",
+                "tracks[1].index: unknown Electrical actuator 1",
+                "tracks[2].index: no actuators",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_start_after_end() {
+        let effect = effect(vec![Track {
+            start_time: 500,
+            end_time: 100,
+            ..track(ActionType::Shake, vec![0])
+        }]);
+        assert_eq!(
+            issues(&effect),
+            ["tracks[0]: start_time 500 is after end_time 100"]
+        );
+    }
+
+    #[test]
+    fn rejects_intensity_above_maximum() {
+        let effect = effect(vec![Track {
+            start_intensity: 150,
+            end_intensity: 151,
+            ..track(ActionType::Shake, vec![0])
+        }]);
+        assert_eq!(
+            issues(&effect),
+            ["tracks[0].end_intensity: 151 is above the maximum of 150"]
+        );
+    }
+
+    #[test]
+    fn rejects_repeating_instant_effect() {
+        let mut effect = effect(vec![Track {
+            end_time: 0,
+            ..track(ActionType::Shake, vec![0])
+        }]);
+        effect.repeat = 0;
+        assert_eq!(
+            issues(&effect),
+            ["repeat: an effect that lasts 0 ms cannot repeat"]
+        );
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let effect = effect(vec![
+            Track {
+                start_time: 500,
+                end_time: 100,
+                start_intensity: 200,
+                ..track(ActionType::Shake, vec![99])
+            },
+            track(ActionType::Electrical, vec![0]),
+            Track {
+                end_intensity: 300,
+                ..track(ActionType::Electrical, vec![7])
+            },
+        ]);
+        assert_eq!(
+            issues(&effect),
+            [
+                "tracks[0].index: unknown Shake actuator 99",
+                "tracks[0]: start_time 500 is after end_time 100",
+                "tracks[0].start_intensity: 200 is above the maximum of 150",
+                "tracks[2].index: unknown Electrical actuator 7",
+                "tracks[2].end_intensity: 300 is above the maximum of 150",
+            ]
+        );
+    }
+}
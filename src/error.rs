@@ -0,0 +1,149 @@
+use std::fmt;
+use uuid::Uuid;
+
+/// Errors returned by the transports, the controller, the encoder/decoder and
+/// the WebSocket layer.
+#[derive(Debug)]
+pub enum TrueGearError {
+    /// No device is connected and none is being searched for.
+    NotConnected,
+    /// No device is connected yet, but a scan for one is in progress.
+    Searching,
+    /// There is no Bluetooth adapter, or none matches the selector.
+    AdapterNotFound(String),
+    /// No matching device was found during a scan.
+    DeviceNotFound(String),
+    /// A request named a device alias the server does not drive.
+    UnknownDevice(String),
+    /// A request named an effect that is not registered.
+    UnknownEffect(String),
+    /// The device does not expose the TrueGear service.
+    ServiceNotFound(Uuid),
+    /// The device does not expose a required characteristic.
+    CharacteristicNotFound(Uuid),
+    /// An effect could not be turned into BLE bytes.
+    Encode(String),
+    /// An effect is well-formed but cannot be played as written; one entry
+    /// per problem found.
+    Validation(Vec<String>),
+    /// An effect was dropped because a higher-priority effect is playing on
+    /// the same actuators.
+    LowerPriority {
+        effect: String,
+        blocker: String,
+    },
+    /// Bytes from the device or a message from a client could not be parsed.
+    Protocol(String),
+    /// The Bluetooth stack reported an error.
+    Bluetooth(btleplug::Error),
+    Io(std::io::Error),
+}
+
+impl TrueGearError {
+    /// A stable identifier for the kind of error, sent to WebSocket clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TrueGearError::NotConnected => "not_connected",
+            TrueGearError::Searching => "searching",
+            TrueGearError::AdapterNotFound(_) => "adapter_not_found",
+            TrueGearError::DeviceNotFound(_) => "device_not_found",
+            TrueGearError::UnknownDevice(_) => "unknown_device",
+            TrueGearError::UnknownEffect(_) => "unknown_effect",
+            TrueGearError::ServiceNotFound(_) => "service_not_found",
+            TrueGearError::CharacteristicNotFound(_) => "characteristic_not_found",
+            TrueGearError::Encode(_) => "encode",
+            TrueGearError::Validation(_) => "validation",
+            TrueGearError::LowerPriority { .. } => "lower_priority",
+            TrueGearError::Protocol(_) => "protocol",
+            TrueGearError::Bluetooth(_) => "bluetooth",
+            TrueGearError::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for TrueGearError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrueGearError::NotConnected => write!(f, "Not connected to device"),
+            TrueGearError::Searching => write!(f, "Searching for device"),
+            TrueGearError::AdapterNotFound(message) => write!(f, "{}", message),
+            TrueGearError::DeviceNotFound(message) => write!(f, "{}", message),
+            TrueGearError::UnknownDevice(alias) => write!(f, "Unknown device: {}", alias),
+            TrueGearError::UnknownEffect(name) => write!(f, "Unknown effect: {}", name),
+            TrueGearError::ServiceNotFound(uuid) => {
+                write!(f, "Failed to find the target BLE service {:?}", uuid)
+            }
+            TrueGearError::CharacteristicNotFound(uuid) => {
+                write!(f, "Failed to find the target BLE characteristic {:?}", uuid)
+            }
+            TrueGearError::Encode(message) => write!(f, "Failed to encode effect: {}", message),
+            TrueGearError::Validation(issues) => {
+                write!(f, "Invalid effect: {}", issues.join("; "))
+            }
+            TrueGearError::LowerPriority { effect, blocker } => write!(
+                f,
+                "Effect {} dropped: {} has a higher priority",
+                effect, blocker
+            ),
+            TrueGearError::Protocol(message) => write!(f, "{}", message),
+            TrueGearError::Bluetooth(e) => write!(f, "Bluetooth error: {}", e),
+            TrueGearError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Bluetooth and I/O errors are carried over by their message, so that every
+/// effect of a coalesced write can report how the write failed.
+impl Clone for TrueGearError {
+    fn clone(&self) -> Self {
+        match self {
+            TrueGearError::NotConnected => TrueGearError::NotConnected,
+            TrueGearError::Searching => TrueGearError::Searching,
+            TrueGearError::AdapterNotFound(message) => {
+                TrueGearError::AdapterNotFound(message.clone())
+            }
+            TrueGearError::DeviceNotFound(message) => {
+                TrueGearError::DeviceNotFound(message.clone())
+            }
+            TrueGearError::UnknownDevice(alias) => TrueGearError::UnknownDevice(alias.clone()),
+            TrueGearError::UnknownEffect(name) => TrueGearError::UnknownEffect(name.clone()),
+            TrueGearError::ServiceNotFound(uuid) => TrueGearError::ServiceNotFound(*uuid),
+            TrueGearError::CharacteristicNotFound(uuid) => {
+                TrueGearError::CharacteristicNotFound(*uuid)
+            }
+            TrueGearError::Encode(message) => TrueGearError::Encode(message.clone()),
+            TrueGearError::Validation(issues) => TrueGearError::Validation(issues.clone()),
+            TrueGearError::LowerPriority { effect, blocker } => TrueGearError::LowerPriority {
+                effect: effect.clone(),
+                blocker: blocker.clone(),
+            },
+            TrueGearError::Protocol(message) => TrueGearError::Protocol(message.clone()),
+            TrueGearError::Bluetooth(e) => {
+                TrueGearError::Bluetooth(btleplug::Error::Other(e.to_string().into()))
+            }
+            TrueGearError::Io(e) => TrueGearError::Io(std::io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+impl std::error::Error for TrueGearError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrueGearError::Bluetooth(e) => Some(e),
+            TrueGearError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<btleplug::Error> for TrueGearError {
+    fn from(e: btleplug::Error) -> Self {
+        TrueGearError::Bluetooth(e)
+    }
+}
+
+impl From<std::io::Error> for TrueGearError {
+    fn from(e: std::io::Error) -> Self {
+        TrueGearError::Io(e)
+    }
+}
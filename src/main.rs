@@ -1,39 +1,298 @@
+use crate::transport::TrueGearTransport;
 use crate::websocket::TureGearWebsocketServer;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
+use tokio::sync::broadcast;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::fmt::MakeWriter;
 
 mod ble;
+mod ble_message_decoder;
 mod ble_message_ext;
 mod ble_notify_parser;
+mod commands;
 mod controller;
+mod device_hub;
+mod effect_library;
+mod effect_registry;
+mod effect_scaling;
+mod effect_timeline;
+mod effect_validation;
+mod error;
+mod mock_transport;
 mod predefined;
+mod simulator;
+mod transport;
 mod true_gear_message;
 mod websocket;
+mod websocket_message;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TransportKind {
+    /// Connect to a TrueGear device over Bluetooth Low Energy
+    Ble,
+    /// Record frames in memory without a device
+    Mock,
+    /// Play frames on a simulated vest that reports battery status
+    Simulator,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the BLE bytes of an effect or command message JSON
+    Encode {
+        #[arg(help = "JSON file to read, or '-' for stdin [default: stdin]")]
+        input: Option<PathBuf>,
+    },
+    /// Decode hex EffectObjects into effect JSON
+    Decode {
+        #[arg(help = "Hex bytes to decode [default: read from stdin]")]
+        hex: Vec<String>,
+
+        #[arg(
+            short,
+            long,
+            default_value_t = false,
+            help = "Print command messages with a base64 body instead of effects"
+        )]
+        message: bool,
+    },
+    /// List nearby TrueGear devices
+    Scan {
+        #[arg(
+            short,
+            long,
+            default_value_t = 5,
+            help = "Time in seconds to scan for devices"
+        )]
+        duration_secs: u64,
+
+        #[arg(long, default_value_t = false, help = "Print the devices as JSON")]
+        json: bool,
+    },
+    /// Play an effect from the library on the device
+    Play {
+        #[arg(help = "Name or uuid of the effect")]
+        name: String,
+
+        #[arg(
+            long,
+            default_value_t = 1.0,
+            help = "Factor applied to every intensity of the effect"
+        )]
+        intensity: f32,
+
+        #[arg(
+            long,
+            default_value_t = 1.0,
+            help = "Factor applied to every track time of the effect"
+        )]
+        duration: f32,
+
+        #[arg(
+            long,
+            help = "Times to play the effect in a row, 0 to loop until interrupted; defaults to the effect's own"
+        )]
+        repeat: Option<u32>,
+
+        #[arg(
+            long,
+            default_value_t = 30,
+            help = "Time in seconds to wait for the device to connect"
+        )]
+        connect_timeout_secs: u64,
+    },
+}
+
+/// A `--device` argument.
+#[derive(Clone, Debug)]
+struct DeviceSpec {
+    alias: String,
+    selector: Option<String>,
+}
+
+fn parse_device_spec(spec: &str) -> Result<DeviceSpec, String> {
+    let (alias, selector) = match spec.split_once('=') {
+        Some((alias, selector)) => (alias, selector),
+        None => (spec, spec),
+    };
+    if alias.is_empty() || selector.is_empty() {
+        return Err("expected [ALIAS=]MAC|NAME".into());
+    }
+    Ok(DeviceSpec {
+        alias: alias.to_string(),
+        selector: Some(selector.to_string()),
+    })
+}
+
+fn parse_device_factor(spec: &str) -> Result<(String, f32), String> {
+    let Some((alias, factor)) = spec.split_once('=') else {
+        return Err("expected ALIAS=FACTOR".into());
+    };
+    let factor = factor
+        .parse::<f32>()
+        .map_err(|e| format!("invalid factor: {}", e))?;
+    Ok((alias.to_string(), factor))
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     // Address to listen on
     #[arg(short, long, default_value_t = String::from("127.0.0.1:18233"), help = "Address to listen on for WebSocket connections")]
     listen_addr: String,
 
     // Strength factor of the Electical effect
-    #[arg(short, long, default_value_t = 1 as f32, help = "Strength factor of the Electical effect (usually between 0.0 to 1.5)")]
+    #[arg(short, long, global = true, default_value_t = 1 as f32, help = "Strength factor of the Electical effect (usually between 0.0 to 1.5)")]
     electical_effect_factor: f32,
 
+    // Backend used to reach the device
+    #[arg(short, long, global = true, value_enum, default_value_t = TransportKind::Ble, help = "Transport used to reach the device")]
+    transport: TransportKind,
+
+    // Bluetooth adapter to use
+    #[arg(
+        long,
+        global = true,
+        value_name = "INDEX|NAME",
+        help = "Bluetooth adapter to use, by index or name [default: first adapter]"
+    )]
+    adapter: Option<String>,
+
+    // Vests to connect to
+    #[arg(
+        long,
+        global = true,
+        value_name = "[ALIAS=]MAC|NAME",
+        value_parser = parse_device_spec,
+        help = "Device to connect to, by MAC address or exact name, optionally named by an alias; repeat to drive several devices [default: last used device, then any TrueGear vest]"
+    )]
+    device: Vec<DeviceSpec>,
+
+    // Per-device strength factor of the Electical effect
+    #[arg(
+        long,
+        global = true,
+        value_name = "ALIAS=FACTOR",
+        value_parser = parse_device_factor,
+        help = "Strength factor of the Electical effect for one device, overriding --electical-effect-factor"
+    )]
+    device_factor: Vec<(String, f32)>,
+
+    // Negotiated MTU of the BLE link; the Bluetooth stack does not report it
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 247,
+        value_parser = clap::value_parser!(u16).range(23..=517),
+        help = "ATT MTU negotiated for the BLE link, which is not detected; writes are split to fit it. Lower it if long effects fail to play"
+    )]
+    ble_mtu: u16,
+
+    // Pause between split writes
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 20,
+        help = "Time in milliseconds between the writes of a split frame"
+    )]
+    ble_write_interval_ms: u64,
+
+    // Retries of failed writes
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 0,
+        help = "Number of times a failed write is retried as a write with response"
+    )]
+    ble_write_retries: u32,
+
+    // Effects held while the device is connecting
+    #[arg(
+        long,
+        default_value_t = 16,
+        help = "Maximum number of effects queued while the device is connecting (0 to disable)"
+    )]
+    pending_capacity: usize,
+
+    // Lifetime of queued effects
+    #[arg(
+        long,
+        default_value_t = 2000,
+        help = "Time in milliseconds after which a queued effect is dropped instead of played"
+    )]
+    pending_ttl_ms: u64,
+
+    // Handling of effects arriving during a higher-priority effect
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = controller::PriorityPolicy::Drop,
+        help = "What to do with an effect arriving while a higher-priority effect plays on the same actuators; with drop it fails with a lower_priority error instead of playing over it"
+    )]
+    priority_policy: controller::PriorityPolicy,
+
+    // Longest wait of a deferred effect
+    #[arg(
+        long,
+        default_value_t = 2000,
+        help = "Time in milliseconds after which a deferred effect is dropped instead of played"
+    )]
+    priority_defer_max_ms: u64,
+
+    // Window for merging effects into one write
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Time in milliseconds during which effects are collected and sent in as few writes as possible (0 to disable)"
+    )]
+    coalesce_window_ms: u64,
+
+    // Directories of effect JSON files
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        help = "Directory of effect JSON files playable by name; repeat to load several"
+    )]
+    library_dir: Vec<PathBuf>,
+
+    // Reload the library on change
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Reload effect files from --library-dir when they are added, modified or removed"
+    )]
+    watch_library: bool,
+
     // show debug logs
-    #[arg(short, long, default_value_t = false, help = "Enable verbose logging")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value_t = false,
+        help = "Enable verbose logging"
+    )]
     verbose: bool,
 }
 
-fn setup_logging(log_level: Level) {
+fn setup_logging<W>(log_level: Level, writer: W)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
     let subscriber = FmtSubscriber::builder()
         // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
-        // will be written to stdout.
+        // will be written to the writer.
         .with_max_level(log_level)
+        .with_writer(writer)
         // completes the builder.
         .finish();
 
@@ -50,15 +309,186 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         log_level = Level::TRACE;
     }
 
-    setup_logging(log_level);
+    // keep stdout clean for the output of subcommands
+    match &args.command {
+        Some(_) => setup_logging(log_level, std::io::stderr),
+        None => setup_logging(log_level, std::io::stdout),
+    }
 
-    let mut true_gear_controller =
-        controller::TrueGearBLEController::build(args.electical_effect_factor).await;
-    true_gear_controller.set_electical_effect_ratio(args.electical_effect_factor);
-    true_gear_controller.start().await?;
+    match &args.command {
+        Some(Command::Encode { input }) => {
+            return commands::encode(input.as_deref(), args.electical_effect_factor);
+        }
+        Some(Command::Decode { hex, message }) => {
+            return commands::decode(hex, args.electical_effect_factor, *message);
+        }
+        Some(Command::Scan {
+            duration_secs,
+            json,
+        }) => {
+            return commands::scan(
+                args.adapter.as_deref(),
+                Duration::from_secs(*duration_secs),
+                *json,
+            )
+            .await;
+        }
+        Some(Command::Play { .. }) | None => {}
+    }
+
+    let library =
+        effect_library::EffectLibrary::load(&args.library_dir).map_err(|e| e.to_string())?;
+    let library = Arc::new(library);
+
+    let device_specs = device_specs(&args)?;
+
+    match args.transport {
+        TransportKind::Ble => {
+            // the vests share the adapter and its scan
+            let scanner = ble::BleScanner::new(args.adapter.clone());
+            let transports = device_specs
+                .iter()
+                .map(|spec| {
+                    let options = ble::BleOptions {
+                        device: spec.selector.clone(),
+                        // minus the ATT header
+                        max_write_size: args.ble_mtu as usize - 3,
+                        write_interval: Duration::from_millis(args.ble_write_interval_ms),
+                        write_retries: args.ble_write_retries,
+                    };
+                    let connection = ble::TrueGearBLEConnection::new(options, scanner.clone());
+                    (spec.alias.clone(), connection)
+                })
+                .collect();
+            run(&args, transports, library).await
+        }
+        TransportKind::Mock => {
+            let transports: Vec<_> = device_specs
+                .iter()
+                .map(|spec| (spec.alias.clone(), mock_transport::MockTransport::new()))
+                .collect();
+            // the transports are shared, so the frames stay readable after
+            // the run
+            let recorded = transports.clone();
+            let result = run(&args, transports, library).await;
+            for (alias, transport) in recorded {
+                let frames = transport.written_frames().await;
+                tracing::info!("Mock device {} recorded {} frames", alias, frames.len());
+            }
+            result
+        }
+        TransportKind::Simulator => {
+            let transports = device_specs
+                .iter()
+                .map(|spec| (spec.alias.clone(), simulator::SimulatorTransport::new()))
+                .collect();
+            run(&args, transports, library).await
+        }
+    }
+}
+
+/// The devices to drive, checking that aliases are unique and that every
+/// `--device-factor` names one of them.
+fn device_specs(args: &Args) -> Result<Vec<DeviceSpec>, Box<dyn Error + Send + Sync>> {
+    let specs = if args.device.is_empty() {
+        vec![DeviceSpec {
+            alias: device_hub::DEFAULT_DEVICE_ALIAS.to_string(),
+            selector: None,
+        }]
+    } else {
+        args.device.clone()
+    };
+
+    for (i, spec) in specs.iter().enumerate() {
+        if specs[..i].iter().any(|other| other.alias == spec.alias) {
+            return Err(format!("Duplicate device alias: {}", spec.alias).into());
+        }
+    }
+
+    for (alias, _) in &args.device_factor {
+        if !specs.iter().any(|spec| spec.alias == *alias) {
+            return Err(format!("--device-factor names an unknown device: {}", alias).into());
+        }
+    }
 
-    let websocket_server =
-        TureGearWebsocketServer::new(args.listen_addr, true_gear_controller.clone());
+    Ok(specs)
+}
+
+/// Plays an effect with the `play` command, or runs the server.
+async fn run<T: TrueGearTransport>(
+    args: &Args,
+    transports: Vec<(String, T)>,
+    library: Arc<effect_library::EffectLibrary>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let device_hub = build_device_hub(args, transports).await;
+
+    match &args.command {
+        Some(Command::Play {
+            name,
+            intensity,
+            duration,
+            repeat,
+            connect_timeout_secs,
+        }) => {
+            commands::play(
+                &device_hub,
+                &library,
+                name,
+                *intensity,
+                *duration,
+                *repeat,
+                Duration::from_secs(*connect_timeout_secs),
+            )
+            .await
+        }
+        _ => run_server(args, device_hub, library).await,
+    }
+}
+
+async fn build_device_hub<T: TrueGearTransport>(
+    args: &Args,
+    transports: Vec<(String, T)>,
+) -> device_hub::DeviceHub<T> {
+    let mut devices = Vec::with_capacity(transports.len());
+    for (alias, transport) in transports {
+        let electical_effect_ratio = args
+            .device_factor
+            .iter()
+            .rev()
+            .find(|(other, _)| *other == alias)
+            .map_or(args.electical_effect_factor, |(_, factor)| *factor);
+
+        let controller = controller::TrueGearBLEController::build(
+            transport,
+            controller::ControllerOptions {
+                electical_effect_ratio,
+                pending_effect_capacity: args.pending_capacity,
+                pending_effect_ttl: Duration::from_millis(args.pending_ttl_ms),
+                priority_policy: args.priority_policy,
+                priority_defer_max: Duration::from_millis(args.priority_defer_max_ms),
+                coalesce_window: Duration::from_millis(args.coalesce_window_ms),
+            },
+        )
+        .await;
+        spawn_device_status_logger(alias.clone(), &controller);
+        devices.push(device_hub::Device { alias, controller });
+    }
+    device_hub::DeviceHub::new(devices)
+}
+
+async fn run_server<T: TrueGearTransport>(
+    args: &Args,
+    device_hub: device_hub::DeviceHub<T>,
+    library: Arc<effect_library::EffectLibrary>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    device_hub.start().await?;
+
+    let websocket_server = TureGearWebsocketServer::new(
+        args.listen_addr.clone(),
+        device_hub.clone(),
+        library,
+        args.watch_library,
+    );
     let websocket_server_clone = websocket_server.clone();
     tokio::spawn(async move {
         if let Err(e) = websocket_server_clone.run().await {
@@ -71,7 +501,36 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     tracing::info!("Ctrl-C received, shutting down.");
 
     websocket_server.close().await?;
-    true_gear_controller.close().await?;
+    device_hub.close().await?;
 
     Ok(())
 }
+
+fn spawn_device_status_logger<T: TrueGearTransport>(
+    alias: String,
+    controller: &controller::TrueGearBLEController<T>,
+) {
+    let mut device_status_receiver = controller.subscribe_device_status();
+    tokio::spawn(async move {
+        loop {
+            let status = match device_status_receiver.recv().await {
+                Ok(status) => status,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            tracing::info!(
+                "Device Status [{}] - Main {}: {}% ({} mV), Left {}: {}% ({} mV), Right {}: {}% ({} mV)",
+                alias,
+                status.main.model,
+                status.main.battery_percent,
+                status.main.battery_mv,
+                status.left.model,
+                status.left.battery_percent,
+                status.left.battery_mv,
+                status.right.model,
+                status.right.battery_percent,
+                status.right.battery_mv
+            );
+        }
+    });
+}
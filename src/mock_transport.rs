@@ -0,0 +1,169 @@
+use crate::error::TrueGearError;
+use crate::transport::{
+    CONNECTION_STATE_CHANNEL_CAPACITY, ConnectionState, DEFAULT_MAX_WRITE_SIZE,
+    OnConnectedCallback, OnMessageReceivedCallback, TrueGearTransport,
+};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::{Mutex, broadcast};
+
+/// How many of the latest written frames the mock keeps.
+const WRITTEN_FRAMES_CAPACITY: usize = 4096;
+
+/// An in-process transport that records every written frame instead of
+/// sending it to a device, and lets notify packets be injected by hand.
+#[derive(Clone)]
+pub struct MockTransport {
+    connected: Arc<Mutex<bool>>,
+    /// The latest written frames, oldest first.
+    written_frames: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    on_connected: Arc<Mutex<Option<OnConnectedCallback>>>,
+    on_message_received: Arc<Mutex<Option<OnMessageReceivedCallback>>>,
+    connection_state_sender: broadcast::Sender<ConnectionState>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        let (connection_state_sender, _) = broadcast::channel(CONNECTION_STATE_CHANNEL_CAPACITY);
+        MockTransport {
+            connected: Arc::new(Mutex::new(false)),
+            written_frames: Arc::new(Mutex::new(VecDeque::new())),
+            on_connected: Arc::new(Mutex::new(None)),
+            on_message_received: Arc::new(Mutex::new(None)),
+            connection_state_sender,
+        }
+    }
+
+    /// Returns a copy of the frames written so far, in order. Only the
+    /// latest 4096 are kept.
+    pub async fn written_frames(&self) -> Vec<Vec<u8>> {
+        self.written_frames.lock().await.iter().cloned().collect()
+    }
+
+    /// Returns the frames written so far and clears the record.
+    #[cfg(test)]
+    pub async fn take_written_frames(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut *self.written_frames.lock().await).into()
+    }
+
+    /// Feeds `data` to the message callback as if the device had sent it.
+    pub async fn inject_notification(&self, data: &[u8]) {
+        tracing::debug!("Injecting notification: {:02X?}", data);
+        if let Some(callback) = &*self.on_message_received.lock().await {
+            callback(data);
+        }
+    }
+
+    /// Simulates the device dropping out or coming back.
+    pub async fn set_connected(&self, connected: bool) {
+        let was_connected = std::mem::replace(&mut *self.connected.lock().await, connected);
+
+        if connected != was_connected {
+            let _ = self.connection_state_sender.send(if connected {
+                ConnectionState::Connected
+            } else {
+                ConnectionState::Disconnected
+            });
+        }
+
+        if connected
+            && !was_connected
+            && let Some(callback) = &*self.on_connected.lock().await
+        {
+            callback();
+        }
+    }
+}
+
+impl TrueGearTransport for MockTransport {
+    async fn set_on_connected<F>(&mut self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        *self.on_connected.lock().await = Some(Box::new(callback));
+    }
+
+    async fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        *self.on_message_received.lock().await = Some(Box::new(callback));
+    }
+
+    async fn start(&mut self) -> Result<(), TrueGearError> {
+        self.connect().await
+    }
+
+    async fn connect(&mut self) -> Result<(), TrueGearError> {
+        tracing::info!("Connected to mock device");
+        self.set_connected(true).await;
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), TrueGearError> {
+        tracing::debug!("Disconnecting from mock device...");
+        self.set_connected(false).await;
+        Ok(())
+    }
+
+    async fn send_data(&mut self, data: &[u8]) -> Result<(), TrueGearError> {
+        if !*self.connected.lock().await {
+            return Err(TrueGearError::NotConnected);
+        }
+
+        tracing::info!("Mock device received ({}): {:02X?}", data.len(), data);
+        let mut written_frames = self.written_frames.lock().await;
+        if written_frames.len() == WRITTEN_FRAMES_CAPACITY {
+            written_frames.pop_front();
+        }
+        written_frames.push_back(data.to_vec());
+        Ok(())
+    }
+
+    fn max_write_size(&self) -> usize {
+        DEFAULT_MAX_WRITE_SIZE
+    }
+
+    async fn connection_state(&self) -> ConnectionState {
+        if *self.connected.lock().await {
+            ConnectionState::Connected
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    fn subscribe_connection_state(&self) -> broadcast::Receiver<ConnectionState> {
+        self.connection_state_sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_latest_written_frames() {
+        let mut transport = MockTransport::new();
+        assert!(matches!(
+            transport.send_data(&[0x00]).await,
+            Err(TrueGearError::NotConnected)
+        ));
+
+        transport.start().await.unwrap();
+        for i in 0..=WRITTEN_FRAMES_CAPACITY {
+            transport
+                .send_data(&(i as u32).to_be_bytes())
+                .await
+                .unwrap();
+        }
+
+        let frames = transport.written_frames().await;
+        assert_eq!(frames.len(), WRITTEN_FRAMES_CAPACITY);
+        // the oldest frame made room for the newest
+        assert_eq!(frames[0], 1u32.to_be_bytes());
+        assert_eq!(
+            frames.last().unwrap(),
+            &(WRITTEN_FRAMES_CAPACITY as u32).to_be_bytes()
+        );
+    }
+}
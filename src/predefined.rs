@@ -101,6 +101,7 @@ pub fn on_connected_message() -> &'static Vec<Message> {
                             114, 115, 116, 117, 118, 119,
                         ],
                     }],
+                    repeat: 1,
                 },
             },
             Message {
@@ -122,6 +123,7 @@ pub fn on_connected_message() -> &'static Vec<Message> {
                         interval: 0,
                         index: vec![0, 100],
                     }],
+                    repeat: 1,
                 },
             },
             Message {
@@ -147,6 +149,7 @@ pub fn on_connected_message() -> &'static Vec<Message> {
                             114, 115, 116, 117, 118, 119,
                         ],
                     }],
+                    repeat: 1,
                 },
             },
             Message {
@@ -168,6 +171,7 @@ pub fn on_connected_message() -> &'static Vec<Message> {
                         interval: 0,
                         index: vec![0, 100],
                     }],
+                    repeat: 1,
                 },
             },
         ]
@@ -0,0 +1,503 @@
+use crate::ble_message_decoder::{self, TrackObject};
+use crate::error::TrueGearError;
+use crate::mock_transport::MockTransport;
+use crate::predefined;
+use crate::transport::{ConnectionState, TrueGearTransport};
+use crate::true_gear_message::ActionType;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, broadcast};
+use tokio::task::JoinHandle;
+
+// How often the simulated vest reports its battery status.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+// Width of a single electrical pulse in Once mode.
+const ELECTRICAL_PULSE_MS: u64 = 50;
+
+const MAIN_MODEL: u16 = 0x0207;
+const SIDE_MODEL: u16 = 0x0105;
+
+#[derive(Debug, Clone)]
+struct SimulatedTrack {
+    received_at_ms: u64,
+    object: TrackObject,
+}
+
+impl SimulatedTrack {
+    fn is_shake(&self) -> bool {
+        self.object.action_type == ActionType::Shake
+    }
+
+    fn is_held(&self) -> bool {
+        self.is_shake() && self.object.keep
+    }
+
+    fn start_ms(&self) -> u64 {
+        self.received_at_ms + self.object.start_time as u64
+    }
+
+    fn end_ms(&self) -> u64 {
+        if self.object.once {
+            self.start_ms() + ELECTRICAL_PULSE_MS
+        } else {
+            self.received_at_ms + self.object.end_time as u64
+        }
+    }
+
+    fn is_finished(&self, at_ms: u64) -> bool {
+        !self.is_held() && at_ms > self.end_ms()
+    }
+
+    fn ramp(&self, at_ms: u64) -> u16 {
+        if !self.object.fade || self.end_ms() <= self.start_ms() {
+            return self.object.start_intensity;
+        }
+        let progress = (at_ms.min(self.end_ms()) - self.start_ms()) as f32
+            / (self.end_ms() - self.start_ms()) as f32;
+        let start = self.object.start_intensity as f32;
+        let end = self.object.end_intensity as f32;
+        (start + (end - start) * progress).round() as u16
+    }
+
+    /// Intensity of this track at `at_ms`, or `None` if it is not driving its
+    /// actuators at that moment.
+    fn intensity_at(&self, at_ms: u64) -> Option<u16> {
+        if at_ms < self.start_ms() {
+            return None;
+        }
+
+        if at_ms > self.end_ms() {
+            return self.is_held().then(|| self.ramp(at_ms));
+        }
+
+        if self.object.once {
+            return (at_ms < self.end_ms()).then_some(self.object.start_intensity);
+        }
+
+        // Pulsed output is modelled as `interval` ms on, `interval` ms off.
+        let interval = self.object.interval as u64;
+        if interval > 0 && ((at_ms - self.start_ms()) / interval) % 2 == 1 {
+            Some(0)
+        } else {
+            Some(self.ramp(at_ms))
+        }
+    }
+}
+
+/// Battery voltages reported by the simulated vest, in mV. A value of 0
+/// reports the module as detached.
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedBattery {
+    pub main_mv: u16,
+    pub left_mv: u16,
+    pub right_mv: u16,
+}
+
+impl Default for SimulatedBattery {
+    fn default() -> Self {
+        SimulatedBattery {
+            main_mv: 4000,
+            left_mv: 3900,
+            right_mv: 3900,
+        }
+    }
+}
+
+/// Intensity of every active actuator at a point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulatedState {
+    pub shake: BTreeMap<u8, u16>,
+    pub electrical: BTreeMap<u8, u16>,
+}
+
+/// A software model of a TrueGear vest.
+///
+/// Times are milliseconds on the simulator's own clock, so callers can feed
+/// frames and query the resulting dot intensities deterministically.
+#[derive(Debug, Clone, Default)]
+pub struct VestSimulator {
+    tracks: Vec<SimulatedTrack>,
+    battery: SimulatedBattery,
+}
+
+impl VestSimulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(test)]
+    pub fn set_battery(&mut self, battery: SimulatedBattery) {
+        self.battery = battery;
+    }
+
+    /// Applies a buffer of one or more EffectObjects received at `at_ms`.
+    pub fn apply_frame(&mut self, data: &[u8], at_ms: u64) -> Result<(), TrueGearError> {
+        self.tracks.retain(|track| !track.is_finished(at_ms));
+
+        for track_objects in ble_message_decoder::read_effect_objects(data)? {
+            for object in track_objects {
+                let track = SimulatedTrack {
+                    received_at_ms: at_ms,
+                    object,
+                };
+                // a newer shake track takes over its dots from any held keep track
+                if track.is_shake() {
+                    self.tracks.retain(|held| {
+                        !(held.is_held()
+                            && at_ms > held.end_ms()
+                            && held
+                                .object
+                                .index
+                                .iter()
+                                .all(|i| track.object.index.contains(i)))
+                    });
+                }
+                self.tracks.push(track);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn intensity_of(&self, action_is_shake: bool, actuator: u8, at_ms: u64) -> u16 {
+        // the most recently received track driving the actuator wins
+        self.tracks
+            .iter()
+            .rev()
+            .filter(|track| track.is_shake() == action_is_shake)
+            .filter(|track| track.object.index.contains(&actuator))
+            .find_map(|track| track.intensity_at(at_ms))
+            .unwrap_or(0)
+    }
+
+    /// Intensity of shake dot `dot` (e.g. `104`) at `at_ms`.
+    pub fn shake_intensity_at(&self, dot: u8, at_ms: u64) -> u16 {
+        self.intensity_of(true, dot, at_ms)
+    }
+
+    /// Intensity of electrical group `group` (`0` or `100`) at `at_ms`.
+    pub fn electrical_intensity_at(&self, group: u8, at_ms: u64) -> u16 {
+        self.intensity_of(false, group, at_ms)
+    }
+
+    /// Every actuator with a non-zero intensity at `at_ms`.
+    pub fn state_at(&self, at_ms: u64) -> SimulatedState {
+        let mut state = SimulatedState::default();
+        for &dot in predefined::shake_flag_shift_map().keys() {
+            let intensity = self.shake_intensity_at(dot, at_ms);
+            if intensity > 0 {
+                state.shake.insert(dot, intensity);
+            }
+        }
+        for &group in predefined::electrical_flag_shift_map().keys() {
+            let intensity = self.electrical_intensity_at(group, at_ms);
+            if intensity > 0 {
+                state.electrical.insert(group, intensity);
+            }
+        }
+        state
+    }
+
+    /// Builds the 0x81 device status notification the real vest sends.
+    pub fn status_notification(&self) -> Vec<u8> {
+        let mut buffer = vec![0x68, 0x68, 0x01, 0x81, 0x02, 0x03, 0x04];
+        for (model, battery_mv) in [
+            (MAIN_MODEL, self.battery.main_mv),
+            (SIDE_MODEL, self.battery.left_mv),
+            (SIDE_MODEL, self.battery.right_mv),
+        ] {
+            buffer.extend(model.to_be_bytes());
+            buffer.extend(battery_mv.to_be_bytes());
+        }
+        buffer.push(0x16);
+        buffer
+    }
+}
+
+/// A transport backed by a `VestSimulator` running on the wall clock.
+#[derive(Clone)]
+pub struct SimulatorTransport {
+    inner: MockTransport,
+    simulator: Arc<Mutex<VestSimulator>>,
+    epoch: Instant,
+    /// The task reporting the battery status while connected.
+    status_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl SimulatorTransport {
+    pub fn new() -> Self {
+        SimulatorTransport {
+            inner: MockTransport::new(),
+            simulator: Arc::new(Mutex::new(VestSimulator::new())),
+            epoch: Instant::now(),
+            status_task: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The simulated vest, on a clock measured in ms since `epoch()`.
+    #[cfg(test)]
+    pub fn simulator(&self) -> Arc<Mutex<VestSimulator>> {
+        self.simulator.clone()
+    }
+
+    #[cfg(test)]
+    pub fn epoch(&self) -> Instant {
+        self.epoch
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    async fn status_loop(self) {
+        let mut interval = tokio::time::interval(STATUS_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.inner.connection_state().await != ConnectionState::Connected {
+                break;
+            }
+
+            let (notification, state) = {
+                let simulator = self.simulator.lock().await;
+                (
+                    simulator.status_notification(),
+                    simulator.state_at(self.now_ms()),
+                )
+            };
+            tracing::debug!("Simulated vest state: {:?}", state);
+            self.inner.inject_notification(&notification).await;
+        }
+    }
+}
+
+impl TrueGearTransport for SimulatorTransport {
+    async fn set_on_connected<F>(&mut self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.inner.set_on_connected(callback).await;
+    }
+
+    async fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.inner.set_on_message_received(callback).await;
+    }
+
+    async fn start(&mut self) -> Result<(), TrueGearError> {
+        self.connect().await
+    }
+
+    async fn connect(&mut self) -> Result<(), TrueGearError> {
+        self.inner.connect().await?;
+        // a loop from an earlier connection may not have noticed it ended
+        let mut status_task = self.status_task.lock().await;
+        if let Some(task) = status_task.take() {
+            task.abort();
+        }
+        *status_task = Some(tokio::spawn(self.clone().status_loop()));
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), TrueGearError> {
+        if let Some(task) = self.status_task.lock().await.take() {
+            task.abort();
+        }
+        self.inner.disconnect().await
+    }
+
+    async fn send_data(&mut self, data: &[u8]) -> Result<(), TrueGearError> {
+        self.inner.send_data(data).await?;
+        self.simulator.lock().await.apply_frame(data, self.now_ms())
+    }
+
+    fn max_write_size(&self) -> usize {
+        self.inner.max_write_size()
+    }
+
+    async fn connection_state(&self) -> ConnectionState {
+        self.inner.connection_state().await
+    }
+
+    fn subscribe_connection_state(&self) -> broadcast::Receiver<ConnectionState> {
+        self.inner.subscribe_connection_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::true_gear_message::{Effect, IntensityMode, Track};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn track(action_type: ActionType, index: u8, end_time: u32, intensity: (u16, u16)) -> Track {
+        Track {
+            start_time: 0,
+            end_time,
+            stop_name: "".into(),
+            start_intensity: intensity.0,
+            end_intensity: intensity.1,
+            intensity_mode: if intensity.0 == intensity.1 {
+                IntensityMode::Const
+            } else {
+                IntensityMode::Fade
+            },
+            action_type,
+            once: false,
+            interval: 0,
+            index: vec![index],
+        }
+    }
+
+    fn frame(keep: bool, tracks: Vec<Track>) -> Vec<u8> {
+        let effect = Effect {
+            name: "test".into(),
+            uuid: "test".into(),
+            keep,
+            priority: 0,
+            tracks,
+            repeat: 1,
+        };
+        let mut buffer = Vec::new();
+        effect.write_ble_bytes_to(&mut buffer, 1.0).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn plays_const_track() {
+        let mut simulator = VestSimulator::new();
+        let track = Track {
+            start_time: 100,
+            ..track(ActionType::Shake, 104, 500, (40, 40))
+        };
+        simulator
+            .apply_frame(&frame(false, vec![track]), 0)
+            .unwrap();
+
+        assert_eq!(simulator.shake_intensity_at(104, 50), 0);
+        assert_eq!(simulator.shake_intensity_at(104, 250), 40);
+        assert_eq!(simulator.shake_intensity_at(104, 500), 40);
+        assert_eq!(simulator.shake_intensity_at(104, 501), 0);
+        assert_eq!(simulator.shake_intensity_at(0, 250), 0);
+    }
+
+    #[test]
+    fn plays_fade_track_from_when_it_arrives() {
+        let mut simulator = VestSimulator::new();
+        let fade = track(ActionType::Shake, 0, 1000, (0, 100));
+        simulator
+            .apply_frame(&frame(false, vec![fade]), 1000)
+            .unwrap();
+
+        assert_eq!(simulator.shake_intensity_at(0, 1250), 25);
+        assert_eq!(simulator.shake_intensity_at(0, 1500), 50);
+        assert_eq!(simulator.shake_intensity_at(0, 2000), 100);
+        assert_eq!(
+            simulator.state_at(1500),
+            SimulatedState {
+                shake: BTreeMap::from([(0, 50)]),
+                electrical: BTreeMap::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn holds_keep_track_until_replaced() {
+        let mut simulator = VestSimulator::new();
+        let held = track(ActionType::Shake, 1, 100, (60, 60));
+        simulator.apply_frame(&frame(true, vec![held]), 0).unwrap();
+        assert_eq!(simulator.shake_intensity_at(1, 5000), 60);
+
+        let hit = track(ActionType::Shake, 1, 100, (30, 30));
+        simulator
+            .apply_frame(&frame(false, vec![hit]), 5000)
+            .unwrap();
+        assert_eq!(simulator.shake_intensity_at(1, 5050), 30);
+        assert_eq!(simulator.shake_intensity_at(1, 5200), 0);
+    }
+
+    #[test]
+    fn pulses_electrical_track_at_its_interval() {
+        let mut simulator = VestSimulator::new();
+        let pulsed = Track {
+            interval: 50,
+            ..track(ActionType::Electrical, 0, 400, (80, 80))
+        };
+        simulator
+            .apply_frame(&frame(false, vec![pulsed]), 0)
+            .unwrap();
+
+        assert_eq!(simulator.electrical_intensity_at(0, 25), 80);
+        assert_eq!(simulator.electrical_intensity_at(0, 75), 0);
+        assert_eq!(simulator.electrical_intensity_at(0, 125), 80);
+        assert_eq!(simulator.electrical_intensity_at(100, 25), 0);
+        assert_eq!(simulator.shake_intensity_at(0, 25), 0);
+    }
+
+    #[test]
+    fn fires_once_track_for_one_pulse() {
+        let mut simulator = VestSimulator::new();
+        let once = Track {
+            once: true,
+            ..track(ActionType::Electrical, 100, 400, (80, 80))
+        };
+        simulator.apply_frame(&frame(false, vec![once]), 0).unwrap();
+
+        assert_eq!(simulator.electrical_intensity_at(100, 10), 80);
+        assert_eq!(simulator.electrical_intensity_at(100, 200), 0);
+    }
+
+    #[test]
+    fn reports_battery() {
+        let mut simulator = VestSimulator::new();
+        simulator.set_battery(SimulatedBattery {
+            main_mv: 4100,
+            left_mv: 0,
+            right_mv: 3500,
+        });
+
+        let notification = simulator.status_notification();
+        assert_eq!(
+            notification[..7],
+            [0x68, 0x68, 0x01, 0x81, 0x02, 0x03, 0x04]
+        );
+        assert_eq!(notification[9..11], 4100u16.to_be_bytes());
+        assert_eq!(notification[13..15], [0x00, 0x00]);
+        assert_eq!(notification[17..19], 3500u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn transport_applies_written_frames() {
+        let mut transport = SimulatorTransport::new();
+        transport.connect().await.unwrap();
+        let hit = track(ActionType::Shake, 104, 60_000, (40, 40));
+        transport.send_data(&frame(false, vec![hit])).await.unwrap();
+
+        let at_ms = transport.epoch().elapsed().as_millis() as u64;
+        let simulator = transport.simulator();
+        assert_eq!(simulator.lock().await.shake_intensity_at(104, at_ms), 40);
+        transport.disconnect().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnecting_keeps_one_status_loop() {
+        let mut transport = SimulatorTransport::new();
+        let notifications = Arc::new(AtomicUsize::new(0));
+        let counter = notifications.clone();
+        transport
+            .set_on_message_received(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .await;
+
+        transport.connect().await.unwrap();
+        transport.inner.set_connected(false).await;
+        transport.connect().await.unwrap();
+
+        // reports at 0, 5 and 10 s
+        tokio::time::sleep(STATUS_INTERVAL * 2 + STATUS_INTERVAL / 2).await;
+        assert_eq!(notifications.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,54 @@
+use crate::error::TrueGearError;
+use serde::Serialize;
+use std::future::Future;
+use tokio::sync::broadcast;
+
+pub type OnConnectedCallback = Box<dyn Fn() + Send + Sync>;
+pub type OnMessageReceivedCallback = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+pub const CONNECTION_STATE_CHANNEL_CAPACITY: usize = 16;
+
+/// Bytes a device is assumed to accept in one write: the ATT payload of the
+/// 247-byte MTU that BLE 4.2 and later stacks usually negotiate.
+pub const DEFAULT_MAX_WRITE_SIZE: usize = 244;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Disconnected,
+    Searching,
+    Connected,
+}
+
+/// A link to a TrueGear device that EffectObjects can be written to and
+/// notifications can be received from.
+///
+/// `TrueGearBLEConnection` is the real backend; `MockTransport` is an
+/// in-process backend for running the server without a device.
+pub trait TrueGearTransport: Clone + Send + Sync + 'static {
+    fn set_on_connected<F>(&mut self, callback: F) -> impl Future<Output = ()> + Send
+    where
+        F: Fn() + Send + Sync + 'static;
+
+    fn set_on_message_received<F>(&mut self, callback: F) -> impl Future<Output = ()> + Send
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static;
+
+    /// Starts the transport, connecting in the background if needed.
+    fn start(&mut self) -> impl Future<Output = Result<(), TrueGearError>> + Send;
+
+    /// Connects to the device and waits until the attempt finishes.
+    fn connect(&mut self) -> impl Future<Output = Result<(), TrueGearError>> + Send;
+
+    fn disconnect(&self) -> impl Future<Output = Result<(), TrueGearError>> + Send;
+
+    fn send_data(&mut self, data: &[u8]) -> impl Future<Output = Result<(), TrueGearError>> + Send;
+
+    /// Largest number of bytes worth putting in one write.
+    fn max_write_size(&self) -> usize;
+
+    fn connection_state(&self) -> impl Future<Output = ConnectionState> + Send;
+
+    /// Receives every connection state transition from now on.
+    fn subscribe_connection_state(&self) -> broadcast::Receiver<ConnectionState>;
+}
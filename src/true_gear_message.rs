@@ -61,7 +61,7 @@ pub struct Message {
     pub body: Effect,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Effect {
     pub name: String,
     pub uuid: String,
@@ -69,12 +69,23 @@ pub struct Effect {
     pub keep: bool,
     pub priority: u16,
     pub tracks: Vec<Track>,
+    /// Times the tracks are played back to back; 0 loops until stopped.
+    #[serde(default = "play_once", skip_serializing_if = "plays_once")]
+    pub repeat: u32,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+fn play_once() -> u32 {
+    1
+}
+
+fn plays_once(repeat: &u32) -> bool {
+    *repeat == 1
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Track {
-    pub start_time: u16,
-    pub end_time: u16,
+    pub start_time: u32,
+    pub end_time: u32,
     pub stop_name: String,
     pub start_intensity: u16,
     pub end_intensity: u16,
@@ -86,13 +97,13 @@ pub struct Track {
     pub index: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ActionType {
     Shake,
     Electrical,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum IntensityMode {
     Const,
     Fade,
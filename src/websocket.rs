@@ -1,42 +1,123 @@
-use crate::controller::TrueGearBLEController;
+use crate::controller::Submitted;
+use crate::device_hub::{Device, DeviceHub};
+use crate::effect_library::{EffectLibrary, LIBRARY_POLL_INTERVAL};
+use crate::effect_registry::{EffectRegistry, RegisterScope};
+use crate::error::TrueGearError;
+use crate::transport::{ConnectionState, TrueGearTransport};
+use crate::true_gear_message;
+use crate::websocket_message::{
+    AckBody, ClockBody, ClockSyncRequest, DeviceAck, ErrorBody, PlayRequest, PlayTiming,
+    RegisterRequest, RequestHeader, ServerMessage, StatusBody, StopRequest,
+};
 use futures::SinkExt;
-use futures::stream::SplitSink;
 use futures_util::StreamExt;
 use std::error::Error;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, broadcast, mpsc};
+use tokio::task::AbortHandle;
 use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::{WebSocketStream, tungstenite};
 
-type WebSocketSink = SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>;
+/// How far ahead a timed effect may be played.
+const PLAY_AT_HORIZON: Duration = Duration::from_secs(24 * 60 * 60);
+/// How many messages may wait to be written to a client before broadcasts to
+/// it are dropped.
+const CLIENT_QUEUE_CAPACITY: usize = 64;
+/// How long a client is given to take its close message before its
+/// connection is dropped.
+const CLIENT_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The queue of messages to be written to a client by its writer task.
+type ClientSender = mpsc::Sender<tungstenite::Message>;
+
+/// What a request led to on each device it was for.
+enum Handled<T: TrueGearTransport> {
+    Done(Vec<DeviceAck>),
+    /// An effect was handed to each device, and may still be waiting for
+    /// the write it goes out in.
+    Played(Vec<(Device<T>, Result<Submitted, TrueGearError>)>),
+}
+
+/// A connected client. Each client is written to by a task of its own, so a
+/// slow client holds up no one else.
+#[derive(Clone)]
+struct Client {
+    addr: SocketAddr,
+    outgoing: ClientSender,
+    writer: AbortHandle,
+}
+
+impl Client {
+    /// Queues a close message and waits for the writer to send it, giving
+    /// up on a client that does not read by stopping its writer.
+    async fn close(&self, reason: &str) {
+        let closed = async {
+            let _ = self
+                .outgoing
+                .send(tungstenite::Message::Close(Some(CloseFrame {
+                    code: tungstenite::protocol::frame::coding::CloseCode::Normal,
+                    reason: reason.into(),
+                })))
+                .await;
+            // the writer ends once the close message is written
+            self.outgoing.closed().await;
+        };
+        if tokio::time::timeout(CLIENT_CLOSE_TIMEOUT, closed)
+            .await
+            .is_err()
+        {
+            tracing::warn!("{} did not take the close message, dropping it", self.addr);
+            self.writer.abort();
+        }
+    }
+}
 
 #[derive(Clone)]
-pub struct TureGearWebsocketServer {
+pub struct TureGearWebsocketServer<T: TrueGearTransport> {
     addr: String,
-    true_gear_controller: TrueGearBLEController,
-    connections_outgoings: Arc<Mutex<Vec<WebSocketSink>>>,
+    device_hub: DeviceHub<T>,
+    clients: Arc<Mutex<Vec<Client>>>,
+    /// Effects registered with the global scope.
+    global_effects: Arc<Mutex<EffectRegistry>>,
+    library: Arc<Mutex<Arc<EffectLibrary>>>,
+    /// Whether to reload the library when its files change.
+    watch_library: bool,
+    /// The origin of the server clock used by `clock_sync` and `PlayAt`.
+    started_at: Instant,
 }
 
-impl TureGearWebsocketServer {
-    pub fn new(addr: String, true_gear_controller: TrueGearBLEController) -> Self {
+impl<T: TrueGearTransport> TureGearWebsocketServer<T> {
+    pub fn new(
+        addr: String,
+        device_hub: DeviceHub<T>,
+        library: Arc<EffectLibrary>,
+        watch_library: bool,
+    ) -> Self {
         TureGearWebsocketServer {
             addr,
-            true_gear_controller,
-            connections_outgoings: Arc::new(Mutex::new(Vec::new())),
+            device_hub,
+            clients: Arc::new(Mutex::new(Vec::new())),
+            global_effects: Arc::new(Mutex::new(EffectRegistry::default())),
+            library: Arc::new(Mutex::new(library)),
+            watch_library,
+            started_at: Instant::now(),
         }
     }
 
-    async fn accept_async_with_path<T: AsyncRead + AsyncWrite + Unpin>(
-        socket: T,
+    async fn accept_async_with_path<S: AsyncRead + AsyncWrite + Unpin>(
+        socket: S,
     ) -> (
-        Result<WebSocketStream<T>, tungstenite::Error>,
+        Result<WebSocketStream<S>, tungstenite::Error>,
         Option<String>,
     ) {
         let mut path = None;
+        // The handshake callback signature is fixed by tungstenite.
+        #[allow(clippy::result_large_err)]
         let callback = |req: &Request, res: Response| -> Result<Response, ErrorResponse> {
             path = Some(req.uri().path().to_string());
             Ok(res)
@@ -54,9 +135,33 @@ impl TureGearWebsocketServer {
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         tracing::info!("Handling v1 connection from: {}", addr);
 
-        let (sink, mut source) = ws_stream.split();
+        let (mut sink, mut source) = ws_stream.split();
+
+        let (outgoing, mut queue) = mpsc::channel(CLIENT_QUEUE_CAPACITY);
+        let writer = tokio::spawn(async move {
+            while let Some(message) = queue.recv().await {
+                let closing = matches!(message, tungstenite::Message::Close(_));
+                let sent = sink.send(message).await;
+                // the client may have gone before the close message
+                if let Err(e) = &sent
+                    && !closing
+                {
+                    tracing::warn!("Failed to send message to {}: {}", addr, e);
+                }
+                if closing || sent.is_err() {
+                    break;
+                }
+            }
+        });
+        let client = Client {
+            addr,
+            outgoing: outgoing.clone(),
+            writer: writer.abort_handle(),
+        };
+        self.clients.lock().await.push(client.clone());
 
-        self.connections_outgoings.lock().await.push(sink);
+        // effects registered with the connection scope
+        let mut effects = EffectRegistry::default();
 
         while let Some(msg) = source.next().await {
             let Ok(msg) = msg else {
@@ -68,21 +173,8 @@ impl TureGearWebsocketServer {
 
             match msg {
                 tungstenite::Message::Text(text) => {
-                    let Ok(control_message) = serde_json::from_str(text.as_str()) else {
-                        tracing::error!("Failed to parse message from {}: {}", addr, text);
-                        continue;
-                    };
-
-                    tracing::debug!("Received a message from {}: {:?}", addr, control_message);
-
-                    match self
-                        .true_gear_controller
-                        .send_ble_message(control_message)
-                        .await
-                    {
-                        Ok(_) => tracing::debug!("Command sent successfully"),
-                        Err(e) => tracing::error!("Failed to send command: {}", e),
-                    }
+                    self.handle_v1_text(&outgoing, addr, &mut effects, text.as_str())
+                        .await;
                 }
                 tungstenite::Message::Close(frame) => {
                     tracing::debug!("Received close message from {}: {:?}", addr, frame);
@@ -99,26 +191,469 @@ impl TureGearWebsocketServer {
 
         tracing::debug!("Closing connection: {}", addr);
 
-        let mut connections_outgoings = self.connections_outgoings.lock().await;
-        if let Some(sink_idx) = connections_outgoings
-            .iter()
-            .position(|e| e.is_pair_of(&source))
-        {
-            let mut sink = connections_outgoings.remove(sink_idx);
-            tracing::debug!("Sending close message to {}", addr);
-            let _ = sink
-                .send(tungstenite::Message::Close(Some(CloseFrame {
-                    code: tungstenite::protocol::frame::coding::CloseCode::Normal,
-                    reason: "Connection closed".into(),
-                })))
-                .await;
-        }
+        self.clients
+            .lock()
+            .await
+            .retain(|client| client.addr != addr);
+        tracing::debug!("Sending close message to {}", addr);
+        client.close("Connection closed").await;
 
         tracing::info!("Connection closed: {}", addr);
 
         Ok(())
     }
 
+    async fn handle_v1_text(
+        &mut self,
+        client: &ClientSender,
+        addr: SocketAddr,
+        effects: &mut EffectRegistry,
+        text: &str,
+    ) {
+        let header = match serde_json::from_str::<RequestHeader>(text) {
+            Ok(header) => header,
+            Err(e) => {
+                tracing::error!("Failed to parse message from {}: {}", addr, text);
+                let error = TrueGearError::Protocol(format!("Invalid message: {}", e));
+                self.send_error(client, None, None, &error).await;
+                return;
+            }
+        };
+
+        let result = match self
+            .handle_request(client, addr, effects, &header, text)
+            .await
+        {
+            Ok(Handled::Played(played))
+                if played
+                    .iter()
+                    .any(|(_, result)| result.as_ref().is_ok_and(Submitted::is_waiting)) =>
+            {
+                // acknowledge once written, without holding up the requests
+                // that could join the same write
+                let server = self.clone();
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let acks = Self::played_acks(played).await;
+                    server.acknowledge(&client, header, Ok(acks)).await;
+                });
+                return;
+            }
+            Ok(Handled::Played(played)) => Ok(Self::played_acks(played).await),
+            Ok(Handled::Done(acks)) => Ok(acks),
+            Err(e) => Err(e),
+        };
+        self.acknowledge(client, header, result).await;
+    }
+
+    /// Answers a request with an `ack` if it has an id, or else with an
+    /// `error` for each device it failed on.
+    async fn acknowledge(
+        &self,
+        client: &ClientSender,
+        header: RequestHeader,
+        result: Result<Vec<DeviceAck>, TrueGearError>,
+    ) {
+        match (header.id, result) {
+            (Some(id), Ok(devices)) => {
+                let ack = AckBody::from_devices(id, &header.method, devices);
+                self.send_to(client, &ServerMessage::Ack(ack)).await;
+            }
+            (Some(id), Err(e)) => {
+                let ack = AckBody::rejected(id, &header.method, &e);
+                self.send_to(client, &ServerMessage::Ack(ack)).await;
+            }
+            (None, Ok(devices)) => {
+                for device in devices.into_iter().filter(|device| !device.ok) {
+                    let error = ErrorBody {
+                        method: Some(header.method.clone()),
+                        device: Some(device.device),
+                        code: device.code.unwrap_or_default(),
+                        message: device.message.unwrap_or_default(),
+                        details: Vec::new(),
+                    };
+                    self.send_to(client, &ServerMessage::Error(error)).await;
+                }
+            }
+            (None, Err(e)) => {
+                self.send_error(client, Some(&header.method), header.device.as_deref(), &e)
+                    .await;
+            }
+        }
+    }
+
+    /// Carries out a request, returning its outcome on every device it was
+    /// for, or the error that stopped it from reaching any. `effects` holds
+    /// the effects the client registered for its connection.
+    async fn handle_request(
+        &mut self,
+        client: &ClientSender,
+        addr: SocketAddr,
+        effects: &mut EffectRegistry,
+        header: &RequestHeader,
+        text: &str,
+    ) -> Result<Handled<T>, TrueGearError> {
+        // these involve no device, so they ignore whichever one is named
+        let devices = match header.method.as_str() {
+            "register" | "clock_sync" => Vec::new(),
+            _ => self
+                .device_hub
+                .select(header.device.as_deref())
+                .inspect_err(|e| tracing::error!("Invalid message from {}: {}", addr, e))?,
+        };
+
+        match header.method.as_str() {
+            "play_no_registered" => {
+                let control_message = Self::parse_effect_message(addr, text)?;
+                let play_at = self.parse_play_time(addr, text)?;
+
+                // reject an invalid effect once rather than once per device
+                control_message
+                    .body
+                    .validate()
+                    .inspect_err(|e| tracing::error!("Invalid effect from {}: {}", addr, e))?;
+
+                Ok(Self::play_on(devices, control_message, play_at).await)
+            }
+            "register" => {
+                let control_message = Self::parse_effect_message(addr, text)?;
+                let request = serde_json::from_str::<RegisterRequest>(text).map_err(|e| {
+                    tracing::error!("Failed to parse message from {}: {}", addr, text);
+                    TrueGearError::Protocol(format!("Invalid register request: {}", e))
+                })?;
+
+                control_message
+                    .body
+                    .validate()
+                    .inspect_err(|e| tracing::error!("Invalid effect from {}: {}", addr, e))?;
+
+                tracing::debug!(
+                    "Registering {} ({:?} scope) from {}",
+                    control_message.body.name,
+                    request.scope,
+                    addr
+                );
+                match request.scope {
+                    RegisterScope::Connection => effects.register(control_message.body),
+                    RegisterScope::Global => self
+                        .global_effects
+                        .lock()
+                        .await
+                        .register(control_message.body),
+                }
+                Ok(Handled::Done(Vec::new()))
+            }
+            "play" => {
+                let request = serde_json::from_str::<PlayRequest>(text).map_err(|e| {
+                    tracing::error!("Failed to parse message from {}: {}", addr, text);
+                    TrueGearError::Protocol(format!("Invalid play request: {}", e))
+                })?;
+                let play_at = self.parse_play_time(addr, text)?;
+
+                // effects registered for the connection shadow global ones,
+                // which shadow the library
+                let registered = match effects.get(&request.body) {
+                    Some(effect) => Some(effect),
+                    None => self.global_effects.lock().await.get(&request.body),
+                };
+                let registered = match registered {
+                    Some(effect) => Some(effect),
+                    None => self.library.lock().await.get(&request.body),
+                };
+                let Some(effect) = registered else {
+                    tracing::warn!("Unknown effect from {}: {}", addr, request.body);
+                    return Err(TrueGearError::UnknownEffect(request.body));
+                };
+
+                let mut effect = effect.scaled(
+                    request.intensity.unwrap_or(1.0),
+                    request.duration.unwrap_or(1.0),
+                )?;
+                if let Some(repeat) = request.repeat {
+                    effect.repeat = repeat;
+                }
+                effect
+                    .validate()
+                    .inspect_err(|e| tracing::error!("Invalid effect from {}: {}", addr, e))?;
+
+                let control_message = true_gear_message::Message {
+                    method: header.method.clone(),
+                    body: effect,
+                };
+                Ok(Self::play_on(devices, control_message, play_at).await)
+            }
+            "stop" | "stop_all" => {
+                let name = match header.method.as_str() {
+                    "stop" => Some(
+                        serde_json::from_str::<StopRequest>(text)
+                            .map_err(|e| {
+                                tracing::error!("Failed to parse message from {}: {}", addr, text);
+                                TrueGearError::Protocol(format!("Invalid stop request: {}", e))
+                            })?
+                            .body,
+                    ),
+                    _ => None,
+                };
+
+                let mut acks = Vec::with_capacity(devices.len());
+                for mut device in devices {
+                    let result = device.controller.stop(name.as_deref()).await;
+                    if let Err(e) = &result {
+                        tracing::error!("Failed to stop effects on {}: {}", device.alias, e);
+                    }
+                    let connection = device.controller.connection_state().await;
+                    acks.push(DeviceAck::new(&device.alias, &result, connection));
+                }
+                Ok(Handled::Done(acks))
+            }
+            "get_status" => {
+                let mut acks = Vec::with_capacity(devices.len());
+                for device in &devices {
+                    let status = Self::status(device).await;
+                    self.send_to(client, &status).await;
+                    let connection = device.controller.connection_state().await;
+                    acks.push(DeviceAck::new(&device.alias, &Ok(None), connection));
+                }
+                Ok(Handled::Done(acks))
+            }
+            "clock_sync" => {
+                let request = serde_json::from_str::<ClockSyncRequest>(text).map_err(|e| {
+                    tracing::error!("Failed to parse message from {}: {}", addr, text);
+                    TrueGearError::Protocol(format!("Invalid clock_sync request: {}", e))
+                })?;
+
+                let clock = ClockBody {
+                    client_time: request.client_time,
+                    server_time: self.started_at.elapsed().as_millis() as u64,
+                };
+                self.send_to(client, &ServerMessage::Clock(clock)).await;
+                Ok(Handled::Done(Vec::new()))
+            }
+            unknown => {
+                tracing::warn!("Received unknown method from {}: {}", addr, unknown);
+                Err(TrueGearError::Protocol(format!(
+                    "Unknown method: {}",
+                    unknown
+                )))
+            }
+        }
+    }
+
+    /// Parses a request whose body is a base64 effect.
+    fn parse_effect_message(
+        addr: SocketAddr,
+        text: &str,
+    ) -> Result<true_gear_message::Message, TrueGearError> {
+        let control_message =
+            serde_json::from_str::<true_gear_message::Message>(text).map_err(|e| {
+                tracing::error!("Failed to parse message from {}: {}", addr, text);
+                TrueGearError::Protocol(format!("Invalid effect: {}", e))
+            })?;
+
+        tracing::debug!("Received a message from {}: {:?}", addr, control_message);
+
+        Ok(control_message)
+    }
+
+    /// When a play request asks to be played, from its `PlayAt` or `DelayMs`;
+    /// `None` to play it straight away.
+    fn parse_play_time(
+        &self,
+        addr: SocketAddr,
+        text: &str,
+    ) -> Result<Option<Instant>, TrueGearError> {
+        let timing = serde_json::from_str::<PlayTiming>(text).map_err(|e| {
+            tracing::error!("Failed to parse message from {}: {}", addr, text);
+            TrueGearError::Protocol(format!("Invalid play timing: {}", e))
+        })?;
+
+        self.play_time(&timing, Instant::now())
+    }
+
+    /// When `timing` asks to be played, as of `now`.
+    fn play_time(
+        &self,
+        timing: &PlayTiming,
+        now: Instant,
+    ) -> Result<Option<Instant>, TrueGearError> {
+        let play_at = match (timing.play_at, timing.delay_ms) {
+            (Some(_), Some(_)) => {
+                return Err(TrueGearError::Protocol(
+                    "PlayAt and DelayMs cannot be used together".to_string(),
+                ));
+            }
+            (Some(play_at), None) => self.started_at.checked_add(Duration::from_millis(play_at)),
+            (None, Some(delay_ms)) => now.checked_add(Duration::from_millis(delay_ms)),
+            (None, None) => return Ok(None),
+        };
+
+        match play_at {
+            Some(play_at) if play_at.saturating_duration_since(now) <= PLAY_AT_HORIZON => {
+                Ok(Some(play_at))
+            }
+            _ => Err(TrueGearError::Protocol(format!(
+                "Effects can be timed at most {} s ahead",
+                PLAY_AT_HORIZON.as_secs()
+            ))),
+        }
+    }
+
+    /// Hands the effect of `control_message` to each of `devices`, to be
+    /// played at `play_at` if given.
+    async fn play_on(
+        devices: Vec<Device<T>>,
+        control_message: true_gear_message::Message,
+        play_at: Option<Instant>,
+    ) -> Handled<T> {
+        let mut played = Vec::with_capacity(devices.len());
+        for mut device in devices {
+            let result = match play_at {
+                Some(play_at) => {
+                    device
+                        .controller
+                        .submit_ble_message_at(control_message.clone(), play_at)
+                        .await
+                }
+                None => {
+                    device
+                        .controller
+                        .submit_ble_message(control_message.clone())
+                        .await
+                }
+            };
+            played.push((device, result));
+        }
+        Handled::Played(played)
+    }
+
+    /// Waits for the effects handed to each device to be written.
+    async fn played_acks(
+        played: Vec<(Device<T>, Result<Submitted, TrueGearError>)>,
+    ) -> Vec<DeviceAck> {
+        let mut acks = Vec::with_capacity(played.len());
+        for (device, result) in played {
+            let result = match result {
+                Ok(submitted) => submitted.written().await,
+                Err(e) => Err(e),
+            };
+            match &result {
+                Ok(Some(_)) => tracing::debug!("Command sent successfully to {}", device.alias),
+                Ok(None) => tracing::debug!("Command deferred or scheduled on {}", device.alias),
+                Err(e @ TrueGearError::LowerPriority { .. }) => {
+                    tracing::debug!("{} on {}", e, device.alias)
+                }
+                Err(e) => tracing::error!("Failed to send command to {}: {}", device.alias, e),
+            }
+            let connection = device.controller.connection_state().await;
+            acks.push(DeviceAck::new(&device.alias, &result, connection));
+        }
+        acks
+    }
+
+    /// Tells `client` that its request failed.
+    async fn send_error(
+        &self,
+        client: &ClientSender,
+        method: Option<&str>,
+        device: Option<&str>,
+        error: &TrueGearError,
+    ) {
+        let message = ServerMessage::Error(ErrorBody::new(method, device, error));
+        self.send_to(client, &message).await;
+    }
+
+    async fn status(device: &Device<T>) -> ServerMessage {
+        let connection = device.controller.connection_state().await;
+        let battery = match connection {
+            ConnectionState::Connected => device.controller.device_status(),
+            _ => None,
+        };
+        ServerMessage::Status(StatusBody {
+            device: device.alias.clone(),
+            connection,
+            battery,
+        })
+    }
+
+    /// Queues `message` for `client`, waiting for room in its queue.
+    async fn send_to(&self, client: &ClientSender, message: &ServerMessage) {
+        let text = message.to_text();
+        if client
+            .send(tungstenite::Message::Text(text.into()))
+            .await
+            .is_err()
+        {
+            tracing::warn!("Failed to send message to client: connection closed");
+        }
+    }
+
+    /// Queues `message` for every connected client, dropping it for those
+    /// whose queue is full rather than waiting on them.
+    async fn broadcast(&self, message: &ServerMessage) {
+        let text = message.to_text();
+        for client in self.clients.lock().await.iter() {
+            let message = tungstenite::Message::Text(text.clone().into());
+            if let Err(mpsc::error::TrySendError::Full(_)) = client.outgoing.try_send(message) {
+                tracing::warn!(
+                    "{} is not keeping up, dropping a message to it",
+                    client.addr
+                );
+            }
+        }
+    }
+
+    /// Pushes a status message for `device` to every client whenever its
+    /// connection state changes or it reports its battery levels.
+    async fn status_push_loop(self, device: Device<T>) {
+        let mut connection_state_receiver = device.controller.subscribe_connection_state();
+        let mut device_status_receiver = device.controller.subscribe_device_status();
+
+        loop {
+            let changed = tokio::select! {
+                result = connection_state_receiver.recv() => result.map(|_| ()),
+                result = device_status_receiver.recv() => result.map(|_| ()),
+            };
+
+            match changed {
+                Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let status = Self::status(&device).await;
+                    self.broadcast(&status).await;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Reloads the effect library whenever its files change, and tells every
+    /// client which effects changed.
+    async fn library_watch_loop(self) {
+        let mut interval = tokio::time::interval(LIBRARY_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let library = self.library.lock().await.clone();
+            let reloaded = match tokio::task::spawn_blocking(move || library.reload()).await {
+                Ok(reloaded) => reloaded,
+                Err(e) => {
+                    tracing::error!("Effect library reload panicked: {}", e);
+                    continue;
+                }
+            };
+
+            match reloaded {
+                Ok(Some((library, changes))) => {
+                    *self.library.lock().await = Arc::new(library);
+                    if !changes.is_empty() {
+                        self.broadcast(&ServerMessage::LibraryChanged(changes))
+                            .await;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to reload the effect library: {}", e),
+            }
+        }
+    }
+
     async fn handle_connection(
         self,
         raw_stream: TcpStream,
@@ -126,8 +661,7 @@ impl TureGearWebsocketServer {
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         tracing::debug!("Incoming TCP connection from: {}", addr);
 
-        let (ws_stream_result, path) =
-            TureGearWebsocketServer::accept_async_with_path(raw_stream).await;
+        let (ws_stream_result, path) = Self::accept_async_with_path(raw_stream).await;
         let mut ws_stream =
             ws_stream_result.expect("Error during the websocket handshake occurred");
 
@@ -170,6 +704,14 @@ impl TureGearWebsocketServer {
         let listener = try_socket.expect("Failed to bind");
         tracing::info!("Listening WebSocket on: {}", self.addr);
 
+        for device in self.device_hub.devices() {
+            tokio::spawn(self.clone().status_push_loop(device.clone()));
+        }
+
+        if self.watch_library {
+            tokio::spawn(self.clone().library_watch_loop());
+        }
+
         // Let's spawn the handling of each connection in a separate task.
         while let Ok((stream, addr)) = listener.accept().await {
             let server_clone = self.clone();
@@ -183,15 +725,373 @@ impl TureGearWebsocketServer {
         tracing::debug!("WebSocket server is shutting down.");
         // close all connections
 
-        let mut connections = self.connections_outgoings.lock().await;
-        for conn in connections.iter_mut() {
-            conn.send(tungstenite::Message::Close(Some(CloseFrame {
-                code: tungstenite::protocol::frame::coding::CloseCode::Normal,
-                reason: "Server is shutting down".into(),
-            })))
-            .await?;
-        }
+        let clients = self.clients.lock().await.clone();
+        futures::future::join_all(
+            clients
+                .iter()
+                .map(|client| client.close("Server is shutting down")),
+        )
+        .await;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{ControllerOptions, PriorityPolicy, TrueGearBLEController};
+    use crate::device_hub::DEFAULT_DEVICE_ALIAS;
+    use crate::effect_library::tests::{TestDir, effect_json, intensity};
+    use crate::mock_transport::MockTransport;
+    use serde_json::{Value, json};
+
+    fn server(library: EffectLibrary) -> TureGearWebsocketServer<MockTransport> {
+        TureGearWebsocketServer::new(
+            "127.0.0.1:0".into(),
+            DeviceHub::new(Vec::new()),
+            Arc::new(library),
+            true,
+        )
+    }
+
+    /// Adds a client to `server`, returning the messages queued for it.
+    async fn client(
+        server: &TureGearWebsocketServer<MockTransport>,
+    ) -> mpsc::Receiver<tungstenite::Message> {
+        let (outgoing, queue) = mpsc::channel(CLIENT_QUEUE_CAPACITY);
+        server.clients.lock().await.push(Client {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            outgoing,
+            writer: tokio::spawn(async {}).abort_handle(),
+        });
+        queue
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_edited_library_and_tells_clients() {
+        let dir = TestDir::new();
+        dir.write("hit.json", &effect_json("hit", "hit", 10));
+        let server = server(dir.load());
+        let mut queue = client(&server).await;
+        tokio::spawn(server.clone().library_watch_loop());
+
+        // wait for a few polls that find nothing to report
+        tokio::time::sleep(LIBRARY_POLL_INTERVAL * 3).await;
+        assert!(queue.try_recv().is_err());
+
+        dir.write("hit.json", &effect_json("hit", "hit", 20));
+        let message = tokio::time::timeout(LIBRARY_POLL_INTERVAL * 2, queue.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            message.into_text().unwrap().as_str(),
+            r#"{"Method":"library_changed","Body":{"added":[],"changed":["hit"],"removed":[]}}"#
+        );
+        assert_eq!(
+            intensity(&server.library.lock().await.clone(), "hit"),
+            Some(20)
+        );
+    }
+
+    /// Serves a single connected mock device, returning the address to
+    /// connect to and the device's transport.
+    async fn serve(library: EffectLibrary) -> (SocketAddr, MockTransport) {
+        let transport = MockTransport::new();
+        let options = ControllerOptions {
+            electical_effect_ratio: 1.0,
+            pending_effect_capacity: 4,
+            pending_effect_ttl: Duration::from_secs(2),
+            priority_policy: PriorityPolicy::Drop,
+            priority_defer_max: Duration::from_secs(2),
+            coalesce_window: Duration::ZERO,
+        };
+        let mut controller = TrueGearBLEController::build(transport.clone(), options).await;
+        controller.start().await.unwrap();
+        let device = Device {
+            alias: DEFAULT_DEVICE_ALIAS.into(),
+            controller,
+        };
+        let server = TureGearWebsocketServer::new(
+            "127.0.0.1:0".into(),
+            DeviceHub::new(vec![device]),
+            Arc::new(library),
+            false,
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                tokio::spawn(server.clone().handle_connection(stream, addr));
+            }
+        });
+        (addr, transport)
+    }
+
+    async fn connect(addr: SocketAddr) -> WebSocketStream<TcpStream> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (ws_stream, _) =
+            tokio_tungstenite::client_async(format!("ws://{addr}/v1/tact/"), stream)
+                .await
+                .unwrap();
+        ws_stream
+    }
+
+    /// Sends `request` and returns the next message the server sends back.
+    async fn request(ws_stream: &mut WebSocketStream<TcpStream>, request: Value) -> Value {
+        ws_stream
+            .send(tungstenite::Message::Text(request.to_string().into()))
+            .await
+            .unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), ws_stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(reply.to_text().unwrap()).unwrap()
+    }
+
+    /// A `register` request for a one-track effect called `name`.
+    fn register_request(name: &str, intensity: u16) -> Value {
+        let effect: true_gear_message::Effect =
+            serde_json::from_str(&effect_json(name, name, intensity)).unwrap();
+        let message = true_gear_message::Message {
+            method: "register".into(),
+            body: effect,
+        };
+        serde_json::to_value(message).unwrap()
+    }
+
+    async fn register(
+        ws_stream: &mut WebSocketStream<TcpStream>,
+        scope: &str,
+        name: &str,
+        intensity: u16,
+    ) {
+        let mut register = register_request(name, intensity);
+        register["Scope"] = json!(scope);
+        register["Id"] = json!(1);
+        let ack = request(ws_stream, register).await;
+        assert_eq!(ack["Body"]["ok"], json!(true), "{}", ack);
+    }
+
+    /// Plays `name`, returning the intensity it was written with, or the
+    /// error code it was rejected with.
+    async fn play(
+        ws_stream: &mut WebSocketStream<TcpStream>,
+        transport: &MockTransport,
+        name: &str,
+    ) -> Result<u16, String> {
+        let ack = request(
+            ws_stream,
+            json!({ "Method": "play", "Id": 2, "Body": name }),
+        )
+        .await;
+        if ack["Body"]["ok"] != json!(true) {
+            return Err(ack["Body"]["code"].as_str().unwrap().to_string());
+        }
+        let frames = transport.take_written_frames().await;
+        // the start intensity of the first TrackObject
+        Ok(frames.last().unwrap()[9] as u16)
+    }
+
+    #[tokio::test]
+    async fn connection_effects_shadow_global_and_library_ones() {
+        let dir = TestDir::new();
+        dir.write("hit.json", &effect_json("hit", "hit", 10));
+        let (addr, transport) = serve(dir.load()).await;
+        let mut first = connect(addr).await;
+        let mut second = connect(addr).await;
+
+        assert_eq!(play(&mut first, &transport, "hit").await, Ok(10));
+        register(&mut first, "global", "hit", 30).await;
+        assert_eq!(play(&mut first, &transport, "hit").await, Ok(30));
+        register(&mut first, "connection", "hit", 60).await;
+        assert_eq!(play(&mut first, &transport, "hit").await, Ok(60));
+
+        // another connection only sees the global effect
+        assert_eq!(play(&mut second, &transport, "hit").await, Ok(30));
+    }
+
+    #[tokio::test]
+    async fn connection_effects_end_with_their_connection() {
+        let (addr, transport) = serve(EffectLibrary::default()).await;
+        let mut first = connect(addr).await;
+        register(&mut first, "connection", "shot", 40).await;
+        register(&mut first, "global", "kick", 50).await;
+        assert_eq!(play(&mut first, &transport, "shot").await, Ok(40));
+
+        let mut second = connect(addr).await;
+        assert_eq!(
+            play(&mut second, &transport, "shot").await,
+            Err("unknown_effect".into())
+        );
+
+        first.close(None).await.unwrap();
+        while first.next().await.is_some() {}
+
+        let mut third = connect(addr).await;
+        assert_eq!(
+            play(&mut third, &transport, "shot").await,
+            Err("unknown_effect".into())
+        );
+        assert_eq!(play(&mut third, &transport, "kick").await, Ok(50));
+    }
+
+    #[tokio::test]
+    async fn device_less_methods_ignore_unknown_device() {
+        let (addr, _transport) = serve(EffectLibrary::default()).await;
+        let mut ws_stream = connect(addr).await;
+
+        let clock = request(
+            &mut ws_stream,
+            json!({ "Method": "clock_sync", "Device": "nope", "ClientTime": 7 }),
+        )
+        .await;
+        assert_eq!(clock["Method"], json!("clock"), "{}", clock);
+        assert_eq!(clock["Body"]["client_time"], json!(7));
+
+        let mut register = register_request("hit", 10);
+        register["Device"] = json!("nope");
+        register["Id"] = json!(1);
+        let ack = request(&mut ws_stream, register).await;
+        assert_eq!(ack["Body"]["ok"], json!(true), "{}", ack);
+
+        let ack = request(
+            &mut ws_stream,
+            json!({ "Method": "play", "Device": "nope", "Id": 2, "Body": "hit" }),
+        )
+        .await;
+        assert_eq!(ack["Body"]["code"], json!("unknown_device"), "{}", ack);
+    }
+
+    fn timing(play_at: Option<u64>, delay_ms: Option<u64>) -> PlayTiming {
+        PlayTiming { play_at, delay_ms }
+    }
+
+    #[test]
+    fn times_effects_up_to_a_day_ahead() {
+        let server = server(EffectLibrary::default());
+        let started_at = server.started_at;
+        let now = started_at + Duration::from_secs(60);
+        let horizon = PLAY_AT_HORIZON.as_millis() as u64;
+
+        assert!(matches!(
+            server.play_time(&timing(None, None), now),
+            Ok(None)
+        ));
+        assert_eq!(
+            server.play_time(&timing(None, Some(horizon)), now).unwrap(),
+            Some(now + PLAY_AT_HORIZON)
+        );
+        assert!(
+            server
+                .play_time(&timing(None, Some(horizon + 1)), now)
+                .is_err()
+        );
+
+        // PlayAt counts from the server start, 60 s before now
+        let play_at = 60_000 + horizon;
+        assert_eq!(
+            server.play_time(&timing(Some(play_at), None), now).unwrap(),
+            Some(now + PLAY_AT_HORIZON)
+        );
+        assert!(
+            server
+                .play_time(&timing(Some(play_at + 1), None), now)
+                .is_err()
+        );
+        assert!(
+            server
+                .play_time(&timing(Some(u64::MAX), None), now)
+                .is_err()
+        );
+        assert!(
+            server
+                .play_time(&timing(None, Some(u64::MAX)), now)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn plays_past_play_at_straight_away() {
+        let server = server(EffectLibrary::default());
+        let now = server.started_at + Duration::from_secs(60);
+
+        // a time in the past is kept, and so is due at once
+        let play_at = server.play_time(&timing(Some(1_000), None), now).unwrap();
+        assert_eq!(play_at, Some(server.started_at + Duration::from_secs(1)));
+        assert!(play_at.unwrap() < now);
+    }
+
+    #[test]
+    fn rejects_invalid_play_timing() {
+        let server = server(EffectLibrary::default());
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let parse = |timing: &str| {
+            let text = format!(r#"{{ "Method": "play", "Body": "hit", {timing} }}"#);
+            match server.parse_play_time(addr, &text) {
+                Err(TrueGearError::Protocol(message)) => message,
+                other => panic!("expected a protocol error, got {:?}", other),
+            }
+        };
+
+        assert_eq!(
+            parse(r#""PlayAt": 10, "DelayMs": 10"#),
+            "PlayAt and DelayMs cannot be used together"
+        );
+        assert!(parse(r#""DelayMs": -5"#).starts_with("Invalid play timing"));
+        assert!(parse(r#""PlayAt": -1"#).starts_with("Invalid play timing"));
+        assert!(parse(r#""DelayMs": 1.5"#).starts_with("Invalid play timing"));
+        assert!(parse(r#""DelayMs": 1e400"#).starts_with("Invalid play timing"));
+        assert!(parse(r#""PlayAt": NaN"#).starts_with("Invalid play timing"));
+        assert!(parse(r#""DelayMs": "Infinity""#).starts_with("Invalid play timing"));
+        assert_eq!(
+            parse(r#""DelayMs": 86400001"#),
+            "Effects can be timed at most 86400 s ahead"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_gives_up_on_client_that_does_not_read() {
+        let server = server(EffectLibrary::default());
+        let (outgoing, queue) = mpsc::channel(CLIENT_QUEUE_CAPACITY);
+        // a writer stuck on a client that stopped reading, with a full queue
+        let writer = tokio::spawn(async move {
+            let _queue = queue;
+            std::future::pending::<()>().await
+        });
+        for _ in 0..CLIENT_QUEUE_CAPACITY {
+            outgoing
+                .try_send(tungstenite::Message::Text("status".into()))
+                .unwrap();
+        }
+        server.clients.lock().await.push(Client {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            outgoing,
+            writer: writer.abort_handle(),
+        });
+
+        let started = tokio::time::Instant::now();
+        server.close().await.unwrap();
+        assert_eq!(started.elapsed(), CLIENT_CLOSE_TIMEOUT);
+        assert!(writer.await.unwrap_err().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn closes_connection_when_client_closes() {
+        let (addr, _transport) = serve(EffectLibrary::default()).await;
+        let mut ws_stream = connect(addr).await;
+        // the server only knows the client once the connection is handled
+        request(&mut ws_stream, json!({ "Method": "clock_sync" })).await;
+
+        ws_stream.close(None).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), async {
+            while ws_stream.next().await.is_some() {}
+        })
+        .await;
+        assert!(closed.is_ok());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::ble_notify_parser::DeviceStatusEvent;
+use crate::effect_library::LibraryChanges;
+use crate::effect_registry::RegisterScope;
+use crate::error::TrueGearError;
+use crate::transport::ConnectionState;
+use serde::{Deserialize, Serialize};
+
+/// The fields shared by every request, used to dispatch on the method before
+/// parsing the rest of the message.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestHeader {
+    #[serde(alias = "Method")]
+    pub method: String,
+    /// Alias of the device the request is for; every device if omitted.
+    #[serde(default, alias = "Device")]
+    pub device: Option<String>,
+    /// Chosen by the client; requests with an id are answered with an `ack`.
+    #[serde(default, alias = "Id")]
+    pub id: Option<serde_json::Value>,
+}
+
+/// A `stop` request; the body names the effect to stop by name or uuid.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StopRequest {
+    #[serde(alias = "Body")]
+    pub body: String,
+}
+
+/// The fields of a `register` request besides its base64 effect body.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegisterRequest {
+    #[serde(default, alias = "Scope")]
+    pub scope: RegisterScope,
+}
+
+/// A `play` request; the body names a registered effect by name or uuid.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayRequest {
+    #[serde(alias = "Body")]
+    pub body: String,
+    /// Factor applied to every intensity of the effect.
+    #[serde(default, alias = "Intensity")]
+    pub intensity: Option<f32>,
+    /// Factor applied to every track time of the effect.
+    #[serde(default, alias = "Duration")]
+    pub duration: Option<f32>,
+    /// Times to play the effect in a row, 0 to loop until stopped, in place
+    /// of the effect's own.
+    #[serde(default, alias = "Repeat")]
+    pub repeat: Option<u32>,
+}
+
+/// When a `play` or `play_no_registered` request is played; straight away if
+/// neither is given.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayTiming {
+    /// Server clock time (see `clock_sync`) to play at, in milliseconds.
+    #[serde(default, alias = "PlayAt")]
+    pub play_at: Option<u64>,
+    /// Time to wait before playing, in milliseconds.
+    #[serde(default, alias = "DelayMs")]
+    pub delay_ms: Option<u64>,
+}
+
+/// A `clock_sync` request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClockSyncRequest {
+    /// The client's clock when it sent the request, echoed back.
+    #[serde(default, alias = "ClientTime")]
+    pub client_time: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusBody {
+    pub device: String,
+    pub connection: ConnectionState,
+    /// Battery levels, present once the connected device has reported them.
+    pub battery: Option<DeviceStatusEvent>,
+}
+
+/// Sent in reply to `clock_sync`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClockBody {
+    /// The `ClientTime` of the request.
+    pub client_time: Option<serde_json::Value>,
+    /// Milliseconds since the server started, on a clock that never jumps.
+    pub server_time: u64,
+}
+
+/// Sent to a client whose request failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorBody {
+    /// The method of the failed request, if it could be read.
+    pub method: Option<String>,
+    /// The device the request failed on, if it was for a single device.
+    pub device: Option<String>,
+    pub code: &'static str,
+    pub message: String,
+    /// Every problem found in an invalid effect.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<String>,
+}
+
+impl ErrorBody {
+    pub fn new(method: Option<&str>, device: Option<&str>, error: &TrueGearError) -> Self {
+        ErrorBody {
+            method: method.map(str::to_string),
+            device: device.map(str::to_string),
+            code: error.code(),
+            message: error.to_string(),
+            details: match error {
+                TrueGearError::Validation(issues) => issues.clone(),
+                _ => Vec::new(),
+            },
+        }
+    }
+}
+
+/// The outcome of a request on one device.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceAck {
+    pub device: String,
+    pub ok: bool,
+    pub code: Option<&'static str>,
+    pub message: Option<String>,
+    /// Size in bytes of the frame written to the device, or queued for it.
+    pub frame_size: Option<usize>,
+    pub connection: ConnectionState,
+}
+
+impl DeviceAck {
+    pub fn new(
+        device: &str,
+        result: &Result<Option<usize>, TrueGearError>,
+        connection: ConnectionState,
+    ) -> Self {
+        DeviceAck {
+            device: device.to_string(),
+            ok: result.is_ok(),
+            code: result.as_ref().err().map(TrueGearError::code),
+            message: result.as_ref().err().map(ToString::to_string),
+            frame_size: result.as_ref().ok().copied().flatten(),
+            connection,
+        }
+    }
+}
+
+/// Sent in reply to every request that carries an id.
+#[derive(Debug, Clone, Serialize)]
+pub struct AckBody {
+    pub id: serde_json::Value,
+    pub method: String,
+    /// Whether the request succeeded on every device it was for.
+    pub ok: bool,
+    /// The first error, if any.
+    pub code: Option<&'static str>,
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<String>,
+    /// One entry per device the request reached; empty if it was rejected
+    /// before reaching any.
+    pub devices: Vec<DeviceAck>,
+}
+
+impl AckBody {
+    /// An ack for a request rejected before it reached a device.
+    pub fn rejected(id: serde_json::Value, method: &str, error: &TrueGearError) -> Self {
+        let error = ErrorBody::new(None, None, error);
+        AckBody {
+            id,
+            method: method.to_string(),
+            ok: false,
+            code: Some(error.code),
+            message: Some(error.message),
+            details: error.details,
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn from_devices(id: serde_json::Value, method: &str, devices: Vec<DeviceAck>) -> Self {
+        let failed = devices.iter().find(|device| !device.ok);
+        AckBody {
+            id,
+            method: method.to_string(),
+            ok: failed.is_none(),
+            code: failed.and_then(|device| device.code),
+            message: failed.and_then(|device| device.message.clone()),
+            details: Vec::new(),
+            devices,
+        }
+    }
+}
+
+/// Messages sent from the server to WebSocket clients.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "Method", content = "Body", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Status(StatusBody),
+    Error(ErrorBody),
+    Ack(AckBody),
+    LibraryChanged(LibraryChanges),
+    Clock(ClockBody),
+}
+
+impl ServerMessage {
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("server messages always serialize")
+    }
+}